@@ -0,0 +1,426 @@
+use std::{env, fs, path::Path, str::FromStr, time::Duration};
+use serde::Deserialize;
+
+use crate::server::{authorisation::cert_cache::CertCachePolicy, invokers_registry::DuplicateSession, settings::Settings, submission::{DeadlinePolicy, MAX_DEADLINE}};
+
+const DEFAULT_CONFIG_PATH: &str = "invoker-manager.toml";
+
+/// Configuration read from TOML file, env vars override values from the file. Durations are in seconds.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub invokers: InvokersConfig,
+    pub testing_systems: TestingSystemsConfig,
+    pub control_panel: ControlPanelConfig,
+    pub authorisation: AuthorisationConfig,
+    pub submissions: SubmissionsConfig,
+    pub websocket: WebSocketConfig,
+    pub protocol: ProtocolConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InvokersConfig {
+    pub address: String,
+    pub auth_method: String,
+    pub ping_interval: u64,
+    pub ping_timeout: u64,
+    pub registry_path: String,
+    pub duplicate_session: String,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_client_ca: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TestingSystemsConfig {
+    pub addresses: Vec<String>,
+    pub api_addresses: Vec<String>,
+    pub ping_interval: u64,
+    pub tls_ca: Option<String>,
+    pub tls_client_cert: Option<String>,
+    pub tls_client_key: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlPanelConfig {
+    pub address: String,
+    pub tokens_path: Option<String>,
+    pub insecure: bool, // allows control panel without tokens
+}
+
+/// Keys of file based auth methods and caching of certificates got with `API` auth method.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthorisationConfig {
+    pub key_file: String,
+    pub keys_dir: String,
+    pub keys_reload_interval: u64,
+    pub revocations_path: String,
+    pub cert_ttl: u64,
+    pub negative_ttl: u64,
+    pub stale_ttl: u64,
+    pub request_timeout: u64,
+    pub request_retries: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubmissionsConfig {
+    pub journal_path: String,
+    pub max_queued: usize,
+    pub max_retries: u8,
+    pub deadline_base: u64,
+    pub deadline_per_test: u64,
+    pub aging_step: u64,
+    pub unroutable_timeout: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    pub max_message_size: usize,
+    pub compression_level: u32,
+}
+
+/// Peers which send more than `error_budget` malformed messages in `error_window` are disconnected.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProtocolConfig {
+    pub error_budget: u32,
+    pub error_window: u64,
+}
+
+impl Default for InvokersConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:1111".to_string(),
+            auth_method: "API".to_string(),
+            ping_interval: 10,
+            ping_timeout: 60,
+            registry_path: "invokers.json".to_string(),
+            duplicate_session: "take_over".to_string(),
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+        }
+    }
+}
+
+impl Default for TestingSystemsConfig {
+    fn default() -> Self {
+        Self {
+            addresses: vec!["127.0.0.1:2222".to_string()],
+            api_addresses: Vec::new(),
+            ping_interval: 30,
+            tls_ca: None,
+            tls_client_cert: None,
+            tls_client_key: None,
+        }
+    }
+}
+
+impl Default for ControlPanelConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:3333".to_string(),
+            tokens_path: None,
+            insecure: false,
+        }
+    }
+}
+
+impl Default for AuthorisationConfig {
+    fn default() -> Self {
+        Self {
+            key_file: "invokers_key.pub".to_string(),
+            keys_dir: "invokers_key".to_string(),
+            keys_reload_interval: 5,
+            revocations_path: "revoked_keys.json".to_string(),
+            cert_ttl: 300,
+            negative_ttl: 30,
+            stale_ttl: 3600,
+            request_timeout: 5,
+            request_retries: 2,
+        }
+    }
+}
+
+impl Default for SubmissionsConfig {
+    fn default() -> Self {
+        Self {
+            journal_path: "submissions.journal".to_string(),
+            max_queued: 10000,
+            max_retries: 3,
+            deadline_base: 300,
+            deadline_per_test: 10,
+            aging_step: 30,
+            unroutable_timeout: 60,
+        }
+    }
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            max_message_size: 1 << 31,
+            compression_level: 9,
+        }
+    }
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            error_budget: 10,
+            error_window: 60,
+        }
+    }
+}
+
+fn list(value: String) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(str::to_string).collect()
+}
+
+fn override_string(target: &mut String, name: &str) {
+    if let Ok(value) = env::var(name) {
+        *target = value;
+    }
+}
+
+fn override_optional(target: &mut Option<String>, name: &str) {
+    if let Ok(value) = env::var(name) {
+        *target = Some(value);
+    }
+}
+
+fn override_bool(target: &mut bool, name: &str) -> Result<(), String> {
+    if let Ok(value) = env::var(name) {
+        *target = value.trim().parse().map_err(|_| format!("{name} should be `true` or `false`, got {value:?}"))?;
+    }
+    Ok(())
+}
+
+fn override_number<T: FromStr>(target: &mut T, name: &str) -> Result<(), String> {
+    if let Ok(value) = env::var(name) {
+        *target = value.trim().parse().map_err(|_| format!("{name} should be a non-negative number, got {value:?}"))?;
+    }
+    Ok(())
+}
+
+impl Config {
+    /// Reads config from `CONFIG_PATH` (or `invoker-manager.toml` if it exists), applies env vars and validates the result.
+    pub fn load() -> Result<Self, String> {
+        let path = env::var("CONFIG_PATH").ok();
+        let mut config = match path.as_deref().or(Path::new(DEFAULT_CONFIG_PATH).exists().then_some(DEFAULT_CONFIG_PATH)) {
+            Some(path) => {
+                let content = fs::read_to_string(path).map_err(|err| format!("Can't read config {path}: {err}"))?;
+                toml::from_str(&content).map_err(|err| format!("Invalid config {path}: {err}"))?
+            },
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), String> {
+        override_string(&mut self.invokers.address, "INVOKERS_ADDRESS");
+        override_string(&mut self.invokers.auth_method, "INVOKERS_AUTH_METHOD");
+        override_number(&mut self.invokers.ping_interval, "INVOKERS_PING_INTERVAL")?;
+        override_number(&mut self.invokers.ping_timeout, "INVOKERS_PING_TIMEOUT")?;
+        override_string(&mut self.invokers.registry_path, "INVOKERS_REGISTRY_PATH");
+        override_string(&mut self.invokers.duplicate_session, "INVOKERS_DUPLICATE_SESSION");
+        override_optional(&mut self.invokers.tls_cert, "INVOKERS_TLS_CERT");
+        override_optional(&mut self.invokers.tls_key, "INVOKERS_TLS_KEY");
+        override_optional(&mut self.invokers.tls_client_ca, "INVOKERS_TLS_CLIENT_CA");
+
+        if let Ok(addresses) = env::var("TS_ADDRESS") {
+            self.testing_systems.addresses = list(addresses);
+        }
+        if let Ok(api_addresses) = env::var("API_ADDRESS") {
+            self.testing_systems.api_addresses = api_addresses.split(',').map(|address| address.trim().to_string()).collect();
+        }
+        override_number(&mut self.testing_systems.ping_interval, "TS_PING_INTERVAL")?;
+        override_optional(&mut self.testing_systems.tls_ca, "TS_TLS_CA");
+        override_optional(&mut self.testing_systems.tls_client_cert, "TS_TLS_CLIENT_CERT");
+        override_optional(&mut self.testing_systems.tls_client_key, "TS_TLS_CLIENT_KEY");
+
+        override_string(&mut self.control_panel.address, "CP_ADDRESS");
+        override_optional(&mut self.control_panel.tokens_path, "CP_TOKENS_PATH");
+        override_bool(&mut self.control_panel.insecure, "CP_INSECURE")?;
+
+        override_string(&mut self.authorisation.key_file, "AUTH_KEY_FILE");
+        override_string(&mut self.authorisation.keys_dir, "AUTH_KEYS_DIR");
+        override_number(&mut self.authorisation.keys_reload_interval, "AUTH_KEYS_RELOAD_INTERVAL")?;
+        override_string(&mut self.authorisation.revocations_path, "AUTH_REVOCATIONS_PATH");
+        override_number(&mut self.authorisation.cert_ttl, "AUTH_CERT_TTL")?;
+        override_number(&mut self.authorisation.negative_ttl, "AUTH_NEGATIVE_TTL")?;
+        override_number(&mut self.authorisation.stale_ttl, "AUTH_STALE_TTL")?;
+        override_number(&mut self.authorisation.request_timeout, "AUTH_REQUEST_TIMEOUT")?;
+        override_number(&mut self.authorisation.request_retries, "AUTH_REQUEST_RETRIES")?;
+
+        override_string(&mut self.submissions.journal_path, "JOURNAL_PATH");
+        override_number(&mut self.submissions.max_queued, "SUBMISSIONS_MAX_QUEUED")?;
+        override_number(&mut self.submissions.max_retries, "SUBMISSIONS_MAX_RETRIES")?;
+        override_number(&mut self.submissions.deadline_base, "SUBMISSIONS_DEADLINE_BASE")?;
+        override_number(&mut self.submissions.deadline_per_test, "SUBMISSIONS_DEADLINE_PER_TEST")?;
+        override_number(&mut self.submissions.aging_step, "SUBMISSIONS_AGING_STEP")?;
+        override_number(&mut self.submissions.unroutable_timeout, "SUBMISSIONS_UNROUTABLE_TIMEOUT")?;
+
+        override_number(&mut self.websocket.max_message_size, "WS_MAX_MESSAGE_SIZE")?;
+        override_number(&mut self.websocket.compression_level, "WS_COMPRESSION_LEVEL")?;
+
+        override_number(&mut self.protocol.error_budget, "PROTOCOL_ERROR_BUDGET")?;
+        override_number(&mut self.protocol.error_window, "PROTOCOL_ERROR_WINDOW")?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        if self.testing_systems.addresses.is_empty() {
+            errors.push("testing_systems.addresses shouldn't be empty".to_string());
+        }
+        if self.invokers.ping_interval == 0 {
+            errors.push("invokers.ping_interval should be positive".to_string());
+        }
+        if self.invokers.ping_timeout <= self.invokers.ping_interval {
+            errors.push(format!("invokers.ping_timeout ({}) should be bigger than invokers.ping_interval ({})", self.invokers.ping_timeout, self.invokers.ping_interval));
+        }
+        if let Err(err) = DuplicateSession::from_str(&self.invokers.duplicate_session) {
+            errors.push(format!("invokers.duplicate_session: {err}"));
+        }
+        if self.invokers.tls_cert.is_some() != self.invokers.tls_key.is_some() {
+            errors.push("invokers.tls_cert and invokers.tls_key should be set together".to_string());
+        }
+        if self.invokers.tls_client_ca.is_some() && self.invokers.tls_cert.is_none() {
+            errors.push("invokers.tls_client_ca requires invokers.tls_cert and invokers.tls_key".to_string());
+        }
+        if self.testing_systems.ping_interval == 0 {
+            errors.push("testing_systems.ping_interval should be positive".to_string());
+        }
+        if self.testing_systems.tls_client_cert.is_some() != self.testing_systems.tls_client_key.is_some() {
+            errors.push("testing_systems.tls_client_cert and testing_systems.tls_client_key should be set together".to_string());
+        }
+        if self.control_panel.tokens_path.is_none() && !self.control_panel.insecure {
+            errors.push("control_panel.tokens_path should be set, control panel can be run without tokens only with control_panel.insecure = true".to_string());
+        }
+        if self.authorisation.keys_reload_interval == 0 {
+            errors.push("authorisation.keys_reload_interval should be positive".to_string());
+        }
+        if self.authorisation.request_timeout == 0 {
+            errors.push("authorisation.request_timeout should be positive".to_string());
+        }
+        if self.submissions.max_queued == 0 {
+            errors.push("submissions.max_queued should be positive".to_string());
+        }
+        if self.submissions.aging_step == 0 {
+            errors.push("submissions.aging_step should be positive".to_string());
+        }
+        if self.submissions.deadline_base > MAX_DEADLINE.as_secs() {
+            errors.push(format!("submissions.deadline_base shouldn't be bigger than {}, got {}", MAX_DEADLINE.as_secs(), self.submissions.deadline_base));
+        }
+        if self.submissions.deadline_per_test > MAX_DEADLINE.as_secs() {
+            errors.push(format!("submissions.deadline_per_test shouldn't be bigger than {}, got {}", MAX_DEADLINE.as_secs(), self.submissions.deadline_per_test));
+        }
+        if self.websocket.max_message_size == 0 {
+            errors.push("websocket.max_message_size should be positive".to_string());
+        }
+        if self.websocket.compression_level > 9 {
+            errors.push(format!("websocket.compression_level should be from 0 to 9, got {}", self.websocket.compression_level));
+        }
+        if self.protocol.error_window == 0 {
+            errors.push("protocol.error_window should be positive".to_string());
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    pub fn settings(&self) -> Settings {
+        Settings {
+            max_message_size: self.websocket.max_message_size,
+            compression_level: self.websocket.compression_level,
+            max_queued_submissions: self.submissions.max_queued,
+            max_submission_retries: self.submissions.max_retries,
+            deadline_policy: DeadlinePolicy {
+                base: Duration::from_secs(self.submissions.deadline_base),
+                per_test: Duration::from_secs(self.submissions.deadline_per_test),
+            },
+            aging_step: Duration::from_secs(self.submissions.aging_step),
+            unroutable_timeout: Duration::from_secs(self.submissions.unroutable_timeout),
+            invokers_ping_interval: Duration::from_secs(self.invokers.ping_interval),
+            invokers_ping_timeout: Duration::from_secs(self.invokers.ping_timeout),
+            duplicate_session: DuplicateSession::from_str(&self.invokers.duplicate_session).unwrap_or(DuplicateSession::TakeOver),
+            testing_systems_ping_interval: Duration::from_secs(self.testing_systems.ping_interval),
+            keys_reload_interval: Duration::from_secs(self.authorisation.keys_reload_interval),
+            cert_cache: CertCachePolicy {
+                ttl: Duration::from_secs(self.authorisation.cert_ttl),
+                negative_ttl: Duration::from_secs(self.authorisation.negative_ttl),
+                stale_ttl: Duration::from_secs(self.authorisation.stale_ttl),
+                retries: self.authorisation.request_retries,
+                retry_delay: Duration::from_millis(500),
+            },
+            protocol_error_budget: self.protocol.error_budget,
+            protocol_error_window: Duration::from_secs(self.protocol.error_window),
+        }
+    }
+
+    /// Names of changed settings which are applied only after restart.
+    pub fn changed_on_restart(&self, other: &Self) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.invokers.address != other.invokers.address { changed.push("invokers.address"); }
+        if self.invokers.auth_method != other.invokers.auth_method { changed.push("invokers.auth_method"); }
+        if self.invokers.registry_path != other.invokers.registry_path { changed.push("invokers.registry_path"); }
+        if (&self.invokers.tls_cert, &self.invokers.tls_key, &self.invokers.tls_client_ca) != (&other.invokers.tls_cert, &other.invokers.tls_key, &other.invokers.tls_client_ca) { changed.push("invokers.tls_*"); }
+        if (&self.testing_systems.addresses, &self.testing_systems.api_addresses) != (&other.testing_systems.addresses, &other.testing_systems.api_addresses) { changed.push("testing_systems.addresses"); }
+        if (&self.testing_systems.tls_ca, &self.testing_systems.tls_client_cert, &self.testing_systems.tls_client_key) != (&other.testing_systems.tls_ca, &other.testing_systems.tls_client_cert, &other.testing_systems.tls_client_key) { changed.push("testing_systems.tls_*"); }
+        if self.control_panel != other.control_panel { changed.push("control_panel"); }
+        if (&self.authorisation.key_file, &self.authorisation.keys_dir) != (&other.authorisation.key_file, &other.authorisation.keys_dir) { changed.push("authorisation.key_file, authorisation.keys_dir"); }
+        if self.authorisation.revocations_path != other.authorisation.revocations_path { changed.push("authorisation.revocations_path"); }
+        if self.authorisation.request_timeout != other.authorisation.request_timeout { changed.push("authorisation.request_timeout"); }
+        if self.submissions.journal_path != other.submissions.journal_path { changed.push("submissions.journal_path"); }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<Config, String> {
+        toml::from_str(content).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn missing_sections_and_settings_are_default() {
+        let config = parse("[invokers]\nping_interval = 5\n[control_panel]\ntokens_path = \"tokens\"\n").unwrap();
+        assert_eq!(config.invokers.ping_interval, 5);
+        assert_eq!(config.invokers.ping_timeout, Config::default().invokers.ping_timeout);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        assert!(parse("[invokers]\nping_intervl = 5\n").is_err());
+        assert!(parse("[invoker]\nping_interval = 5\n").is_err());
+    }
+
+    #[test]
+    fn every_invalid_setting_is_reported() {
+        let mut config = Config::default();
+        config.invokers.ping_timeout = config.invokers.ping_interval;
+        config.invokers.duplicate_session = "kick".to_string();
+        config.invokers.tls_cert = Some("invokers.crt".to_string());
+        config.submissions.deadline_base = MAX_DEADLINE.as_secs() + 1;
+        config.websocket.compression_level = 10;
+        let errors = config.validate().unwrap_err();
+        for setting in ["invokers.ping_timeout", "invokers.duplicate_session", "invokers.tls_key", "control_panel.tokens_path", "submissions.deadline_base", "websocket.compression_level"] {
+            assert!(errors.contains(setting), "{setting} isn't reported in {errors}");
+        }
+    }
+}
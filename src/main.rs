@@ -1,28 +1,129 @@
+mod config;
 mod server;
 
-use std::{env, str::FromStr};
-use server::{authorisation::Authorisation, control_panel::ControlPanel, invokers_side::InvokersSide, testing_system_side::TestingSystemSide, Server};
-
-pub const MAX_MESSAGE_SIZE: usize = 1 << 31;
-pub const COMPRESSION_LEVEL: u32 = 9;
+use std::{sync::Arc, time::Duration};
+use config::Config;
+use server::{authorisation::{keyring::Keyring, revocations::Revocations, Authorisation}, control_panel::{tokens::Tokens, ControlPanel}, invokers_registry::InvokersRegistry, invokers_side::InvokersSide, journal::Journal, testing_system_side::{Endpoint, TestingSystemSide}, tls::{ClientTlsConfig, TlsConfig}, Server};
+use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    let inv_address: String = env::var("INVOKERS_ADDRESS").unwrap_or("127.0.0.1:1111".to_string());
-    let ts_address: String = env::var("TS_ADDRESS").unwrap_or("127.0.0.1:2222".to_string());
-    let cp_address: String = env::var("CP_ADDRESS").unwrap_or("127.0.0.1:3333".to_string());
-    let api_address: String = env::var("API_ADDRESS").unwrap_or(format!("{ts_address}/api"));
-    let inv_auth_method: String = env::var("INVOKERS_AUTH_METHOD").unwrap_or(format!("API"));
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!("Invalid configuration: {err}");
+            return;
+        }
+    };
+    log::info!("Configuration loaded | config = {:?}", config);
+    let ts_tls = ClientTlsConfig {
+        ca_path: config.testing_systems.tls_ca.clone().map(Into::into),
+        cert_path: config.testing_systems.tls_client_cert.clone().map(Into::into),
+        key_path: config.testing_systems.tls_client_key.clone().map(Into::into),
+    };
+
+    let cp_tokens = match config.control_panel.tokens_path.as_ref().map(Tokens::from_file).transpose() {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            log::error!("Can't load control panel tokens: {err}");
+            return;
+        }
+    };
+    let ts_tls = match ts_tls.load() {
+        Ok(ts_tls) => Arc::new(ts_tls),
+        Err(err) => {
+            log::error!("Can't load TLS config for testing systems: {err}");
+            return;
+        }
+    };
+    let api_client = match reqwest::Client::builder()
+        .tls_backend_preconfigured(ts_tls.as_ref().clone())
+        .connect_timeout(Duration::from_secs(config.authorisation.request_timeout))
+        .timeout(Duration::from_secs(config.authorisation.request_timeout))
+        .build() {
+        Ok(api_client) => api_client,
+        Err(err) => {
+            log::error!("Can't create API client: {err}");
+            return;
+        }
+    };
+    let api_addresses = &config.testing_systems.api_addresses;
+    let ts_endpoints: Vec<Endpoint> = config.testing_systems.addresses.iter().map(String::as_str).enumerate().map(|(i, address)| {
+        let (secure, address) = match address.strip_prefix("wss://") {
+            Some(address) => (true, address),
+            None => (false, address.strip_prefix("ws://").unwrap_or(address)),
+        };
+        let (ws_scheme, http_scheme) = if secure { ("wss", "https") } else { ("ws", "http") };
+        Endpoint {
+            address: address.to_string(),
+            url: format!("{ws_scheme}://{address}/api/ws/setup"),
+            api_url: api_addresses.get(i).filter(|api_address| !api_address.is_empty()).map_or(format!("{http_scheme}://{address}/api"), |api_address| {
+                if api_address.contains("://") { api_address.to_string() } else { format!("{http_scheme}://{api_address}") }
+            }),
+            api_client: api_client.clone(),
+            tls: secure.then(|| ts_tls.clone()),
+        }
+    }).collect();
+
+    let (journal, recovered) = match Journal::open(&config.submissions.journal_path) {
+        Ok(journal) => journal,
+        Err(err) => {
+            log::error!("Can't open submissions journal: {err}");
+            return;
+        }
+    };
+
+    let inv_tls = match (&config.invokers.tls_cert, &config.invokers.tls_key) {
+        (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            client_ca_path: config.invokers.tls_client_ca.clone().map(Into::into),
+        }),
+        _ => None,
+    };
+
+    let authorisation = match Authorisation::new(&config.invokers.auth_method, config.authorisation.key_file.clone().into(), config.authorisation.keys_dir.clone().into()) {
+        Ok(authorisation) => authorisation,
+        Err(err) => {
+            log::error!("Can't load invokers' keys: {err}");
+            return;
+        }
+    };
+    let keyring = authorisation.keyring().cloned();
+    let revocations = match Revocations::load(&config.authorisation.revocations_path) {
+        Ok(revocations) => revocations,
+        Err(err) => {
+            log::error!("Can't load revoked keys: {err}");
+            return;
+        }
+    };
+
+    let invokers_registry = match InvokersRegistry::load(&config.invokers.registry_path) {
+        Ok(invokers_registry) => invokers_registry,
+        Err(err) => {
+            log::error!("Can't load invokers registry: {err}");
+            return;
+        }
+    };
 
-    let server = Server::new(Authorisation::from_str(&inv_auth_method).unwrap_or(Authorisation::API));
+    let server = Server::new(authorisation, revocations, invokers_registry, journal, config.settings());
     log::info!("Server created");
+    if let Some(keyring) = keyring {
+        tokio::spawn(Keyring::watcher(keyring, server.clone()));
+    }
+    Server::recover(server.clone(), recovered).await;
+    tokio::spawn(Journal::compactor(server.clone()));
+    tokio::spawn(InvokersSide::unroutable_sweeper(server.clone()));
+    tokio::spawn(reload_on_sighup(server.clone(), config.clone()));
+    let inv_address = config.invokers.address.clone();
+    let cp_address = config.control_panel.address.clone();
     let inv_side = {
         let server = server.clone();
         tokio::spawn(async move {
             log::info!("Invoker side started");
-            if let Err(err) = InvokersSide::start(server, inv_address).await {
+            if let Err(err) = InvokersSide::start(server, inv_address, inv_tls).await {
             //if let Err(err) = Server::start_invokers_side(server_cl, "192.168.1.128:5477".to_string()).await {
                 log::error!("Invokers side stoped with error | error = {}", err);
             }
@@ -31,17 +132,24 @@ async fn main() {
     let ts_side = {
         let server = server.clone();
         tokio::spawn(async move {
-            log::info!("Testing system side started");
-            if let Err(err) =  TestingSystemSide::start(server, &ts_address, &api_address, &format!("ws://{ts_address}/api/ws/setup")).await {
-                log::error!("Testing system side stoped with error | error = {}", err);
-            };
+            let mut ts_connections = tokio::task::JoinSet::new();
+            for endpoint in ts_endpoints {
+                let server = server.clone();
+                ts_connections.spawn(async move {
+                    log::info!("Testing system side started | address = {}", endpoint.address);
+                    if let Err(err) = TestingSystemSide::start(server, endpoint).await {
+                        log::error!("Testing system side stoped with error | error = {}", err);
+                    };
+                });
+            }
+            ts_connections.join_all().await;
         })
     };
     let control_panel = {
         let server = server.clone();
         tokio::spawn(async move {
             log::info!("Control panel started");
-            let control_panel = match ControlPanel::binded_to(&cp_address, server).await {
+            let control_panel = match ControlPanel::binded_to(&cp_address, server, cp_tokens).await {
                 Ok(cp) => cp,
                 Err(er) => {
                     log::error!("Control panel hasn't binded: {er}");
@@ -57,3 +165,30 @@ async fn main() {
     tokio::try_join!(inv_side, ts_side, control_panel).unwrap();
 }
 
+
+/// Reloads configuration on SIGHUP, settings which can't be changed at runtime are only reported.
+async fn reload_on_sighup(server: Arc<tokio::sync::Mutex<Server>>, mut config: Config) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            log::error!("Can't listen for SIGHUP, configuration won't be reloaded: {err}");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        log::info!("Received SIGHUP, reloading configuration");
+        let reloaded = match Config::load() {
+            Ok(reloaded) => reloaded,
+            Err(err) => {
+                log::error!("Invalid configuration, keeping the previous one: {err}");
+                continue;
+            }
+        };
+        let changed_on_restart = reloaded.changed_on_restart(&config);
+        if !changed_on_restart.is_empty() {
+            log::warn!("Some settings are applied only after restart | settings = {}", changed_on_restart.join(", "));
+        }
+        Server::apply_settings(server.clone(), reloaded.settings()).await;
+        config = reloaded;
+    }
+}
@@ -0,0 +1,127 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+
+use invoker_auth::Cert;
+use tokio::sync::Mutex;
+
+use crate::server::testing_system::{self, TestingSystem};
+
+/// Why certificate couldn't be got from testing system.
+#[derive(Debug, Clone)]
+pub enum CertError {
+    Unknown(String), // testing system answered that it doesn't know the key
+    Unavailable(String), // testing system couldn't be asked
+}
+
+/// How long certificates got from testing systems are kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertCachePolicy {
+    pub ttl: Duration,
+    pub negative_ttl: Duration, // for keys which testing systems don't know
+    pub stale_ttl: Duration, // expired certificate is still used for this long while testing systems are unavailable
+    pub retries: u32,
+    pub retry_delay: Duration, // doubled after every retry
+}
+
+enum Entry {
+    Found { cert: Cert, fetched_at: Instant },
+    Unknown { fetched_at: Instant },
+}
+
+/// Certificates of invokers' keys got from testing systems API.
+pub struct CertCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    fetching: Mutex<HashMap<String, Arc<Mutex<()>>>>, // concurrent connections with the same key make only one request
+}
+
+impl CertCache {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            fetching: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn get(&self, key: &str, testing_systems: Vec<Arc<Mutex<TestingSystem>>>, policy: &CertCachePolicy) -> Result<Cert, String> {
+        let lock = self.fetching.lock().await.entry(key.to_string()).or_default().clone();
+        let result = {
+            let _guard = lock.lock().await;
+            self.get_locked(key, testing_systems, policy).await
+        };
+        drop(lock);
+        let mut fetching = self.fetching.lock().await;
+        if fetching.get(key).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+            fetching.remove(key);
+        }
+        result
+    }
+
+    async fn get_locked(&self, key: &str, testing_systems: Vec<Arc<Mutex<TestingSystem>>>, policy: &CertCachePolicy) -> Result<Cert, String> {
+        match self.entries.lock().await.get(key) {
+            Some(Entry::Found { cert, fetched_at }) if fetched_at.elapsed() < policy.ttl => {
+                log::trace!("cert_cache: Hit | key = {}", key);
+                return Ok(cert.clone());
+            },
+            Some(Entry::Unknown { fetched_at }) if fetched_at.elapsed() < policy.negative_ttl => {
+                return Err("Testing systems don't know the key (cached)".to_string());
+            },
+            _ => {},
+        }
+
+        let result = Self::fetch(key, testing_systems, policy).await;
+        let mut entries = self.entries.lock().await;
+        entries.retain(|_, entry| match entry {
+            Entry::Found { fetched_at, .. } => fetched_at.elapsed() < policy.ttl + policy.stale_ttl,
+            Entry::Unknown { fetched_at } => fetched_at.elapsed() < policy.negative_ttl,
+        });
+        match result {
+            Ok(cert) => {
+                entries.insert(key.to_string(), Entry::Found { cert: cert.clone(), fetched_at: Instant::now() });
+                Ok(cert)
+            },
+            Err(CertError::Unknown(err)) => {
+                entries.insert(key.to_string(), Entry::Unknown { fetched_at: Instant::now() });
+                Err(err)
+            },
+            Err(CertError::Unavailable(err)) => match entries.get(key) {
+                Some(Entry::Found { cert, fetched_at }) => {
+                    log::warn!("cert_cache: Testing systems are unavailable, stale certificate is used | key = {} | age = {:?} | error = {}", key, fetched_at.elapsed(), err);
+                    Ok(cert.clone())
+                },
+                _ => Err(err),
+            },
+        }
+    }
+
+    /// Asks every testing system until one of them knows the key, unavailable ones are retried with backoff.
+    async fn fetch(key: &str, testing_systems: Vec<Arc<Mutex<TestingSystem>>>, policy: &CertCachePolicy) -> Result<Cert, CertError> {
+        if testing_systems.is_empty() {
+            return Err(CertError::Unavailable("Trying to get key from API, but testing system hasn't connected yet".to_string()));
+        }
+        let mut errors = Vec::new();
+        let mut unavailable = false;
+        for testing_system in testing_systems {
+            let mut delay = policy.retry_delay;
+            for attempt in 0..=policy.retries {
+                match testing_system::gateway::Gateway::get_certificate_by_key(testing_system.clone(), key).await {
+                    Ok(cert) => return Ok(cert),
+                    Err(CertError::Unknown(err)) => {
+                        errors.push(err);
+                        break;
+                    },
+                    Err(CertError::Unavailable(err)) => {
+                        log::warn!("cert_cache: Testing system API is unavailable | error = {} | attempt = {}", err, attempt + 1);
+                        if attempt == policy.retries {
+                            errors.push(err);
+                            unavailable = true;
+                        } else {
+                            tokio::time::sleep(delay).await;
+                            delay *= 2;
+                        }
+                    },
+                }
+            }
+        }
+        let message = format!("No testing system gave certificate: {}", errors.join("; "));
+        Err(if unavailable { CertError::Unavailable(message) } else { CertError::Unknown(message) })
+    }
+}
@@ -0,0 +1,164 @@
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+
+use invoker_auth::{Cert, Parse};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::server::Server;
+
+/// Key names are used as file names, so only `[A-Za-z0-9_.-]` not starting with `.` are allowed.
+pub fn validate_key_name(key: &str) -> Result<(), String> {
+    if key.is_empty() || key.len() > 128 {
+        return Err(format!("Key name should be from 1 to 128 characters, got {}", key.len()));
+    }
+    if key.starts_with('.') {
+        return Err("Key name shouldn't start with '.'".to_string());
+    }
+    if let Some(c) = key.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))) {
+        return Err(format!("Key name contains forbidden character {c:?}"));
+    }
+    Ok(())
+}
+
+/// Where keys are loaded from.
+#[derive(Debug, Clone)]
+pub enum KeySource {
+    File(PathBuf), // one certificate for every key
+    Directory(PathBuf), // certificate of key `name` is `<directory>/<name>.pub`
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LoadedKey {
+    pub name: String,
+    pub path: PathBuf,
+    pub modified: Option<u64>, // unix time in seconds
+    #[serde(skip)]
+    cert: Cert,
+}
+
+type Stamp = (PathBuf, Option<SystemTime>, u64);
+
+struct State {
+    keys: BTreeMap<String, LoadedKey>,
+    stamps: Vec<Stamp>,
+}
+
+/// Certificates of invokers' keys loaded from disk, reloaded when files change.
+pub struct Keyring {
+    source: KeySource,
+    state: Mutex<State>,
+}
+
+impl Keyring {
+    pub fn load(source: KeySource) -> Result<Self, String> {
+        let stamps = Self::stamps(&source)?;
+        let keys = Self::read(&source, &stamps, &BTreeMap::new());
+        log::info!("keyring: Keys loaded | source = {:?} | keys = {}", source, keys.len());
+        Ok(Self { source, state: Mutex::new(State { keys, stamps }) })
+    }
+
+    /// Files which disappear while directory is listed are skipped.
+    fn stamps(source: &KeySource) -> Result<Vec<Stamp>, String> {
+        let mut stamps = match source {
+            KeySource::File(path) => {
+                let metadata = path.metadata().map_err(|err| format!("Can't read key {}: {err}", path.display()))?;
+                vec![(path.clone(), metadata.modified().ok(), metadata.len())]
+            },
+            KeySource::Directory(directory) => std::fs::read_dir(directory)
+                .map_err(|err| format!("Can't read keys directory {}: {err}", directory.display()))?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|extension| extension == "pub"))
+                .filter_map(|path| {
+                    let metadata = path.metadata().ok().filter(|metadata| metadata.is_file())?;
+                    Some((path, metadata.modified().ok(), metadata.len()))
+                })
+                .collect(),
+        };
+        stamps.sort();
+        Ok(stamps)
+    }
+
+    /// Files which can't be parsed keep their previous certificate.
+    fn read(source: &KeySource, stamps: &[Stamp], previous: &BTreeMap<String, LoadedKey>) -> BTreeMap<String, LoadedKey> {
+        let mut keys = BTreeMap::new();
+        for (path, modified, _) in stamps {
+            let name = path.file_stem().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+            if let (KeySource::Directory(_), Err(err)) = (source, validate_key_name(&name)) {
+                log::warn!("keyring: Key file is skipped | path = {} | error = {}", path.display(), err);
+                continue;
+            }
+            let cert = match Cert::from_file(path) {
+                Ok(cert) => cert,
+                Err(_) => match previous.get(&name) {
+                    Some(key) => {
+                        log::error!("keyring: Can't parse key, the previous one is used | path = {}", path.display());
+                        keys.insert(name, key.clone());
+                        continue;
+                    },
+                    None => {
+                        log::error!("keyring: Can't parse key | path = {}", path.display());
+                        continue;
+                    },
+                },
+            };
+            let modified = modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()).map(|modified| modified.as_secs());
+            keys.insert(name.clone(), LoadedKey { name, path: path.clone(), modified, cert });
+        }
+        keys
+    }
+
+    /// Reloads keys if any file was added, removed or changed, if files can't be listed the previous keys are kept.
+    pub async fn reload(&self) -> Result<(), String> {
+        let stamps = Self::stamps(&self.source)?;
+        let mut state = self.state.lock().await;
+        if stamps == state.stamps {
+            return Ok(());
+        }
+        let keys = Self::read(&self.source, &stamps, &state.keys);
+        log::info!("keyring: Keys reloaded | source = {:?} | keys = {}", self.source, keys.len());
+        state.keys = keys;
+        state.stamps = stamps;
+        Ok(())
+    }
+
+    /// Key is used as file name only with `Directory` source, so only then it's validated.
+    pub async fn get(&self, key: &str) -> Result<Cert, String> {
+        if let KeySource::Directory(_) = self.source {
+            validate_key_name(key)?;
+        }
+        let state = self.state.lock().await;
+        match &self.source {
+            KeySource::File(_) => state.keys.values().next(),
+            KeySource::Directory(_) => state.keys.get(key),
+        }.map(|key| key.cert.clone()).ok_or_else(|| format!("Key {key} isn't loaded"))
+    }
+
+    pub async fn list(&self) -> Vec<LoadedKey> {
+        self.state.lock().await.keys.values().cloned().collect()
+    }
+
+    pub async fn watcher(keyring: Arc<Self>, server: Arc<Mutex<Server>>) {
+        loop {
+            let interval = server.lock().await.settings.keys_reload_interval;
+            tokio::time::sleep(interval).await;
+            if let Err(err) = keyring.reload().await {
+                log::error!("keyring: Couldn't reload keys, the previous ones are used | error = {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_names_are_plain_file_names() {
+        assert!(validate_key_name("judge-1.main_key").is_ok());
+        for name in ["", ".hidden", "../etc/passwd", "dir/key", "key name", "ключ"] {
+            assert!(validate_key_name(name).is_err(), "{name:?} is accepted");
+        }
+        assert!(validate_key_name(&"k".repeat(128)).is_ok());
+        assert!(validate_key_name(&"k".repeat(129)).is_err());
+    }
+}
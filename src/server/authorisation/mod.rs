@@ -0,0 +1,58 @@
+pub mod cert_cache;
+pub mod keyring;
+pub mod revocations;
+
+use std::{path::PathBuf, sync::Arc};
+
+use invoker_auth::Cert;
+use tokio::sync::Mutex;
+
+use cert_cache::{CertCache, CertCachePolicy};
+use keyring::{KeySource, Keyring};
+use super::testing_system::TestingSystem;
+
+#[derive(Clone)]
+pub enum Authorisation {
+    API(Arc<CertCache>),
+    FromFile(Arc<Keyring>),
+    FromFileByName(Arc<Keyring>),
+}
+
+impl Authorisation {
+    /// Keys of file based methods are loaded immediately, `FromFile` uses `key_file` for every key and `FromFileByName` uses `<keys_dir>/<key>.pub`.
+    pub fn new(method: &str, key_file: PathBuf, keys_dir: PathBuf) -> Result<Self, String> {
+        Ok(match method {
+            "File" | "FromFile" | "file" | "fromfile" | "FILE" | "FROMFILE" => Self::FromFile(Arc::new(Keyring::load(KeySource::File(key_file))?)),
+            "FromFileByName" | "ByName" | "fromfilebyname" | "byname" | "FROMFILEBYNAME" | "BYNAME" => Self::FromFileByName(Arc::new(Keyring::load(KeySource::Directory(keys_dir))?)),
+            _ => Self::API(Arc::new(CertCache::new())),
+        })
+    }
+
+    pub fn keyring(&self) -> Option<&Arc<Keyring>> {
+        match self {
+            Self::API(_) => None,
+            Self::FromFile(keyring) | Self::FromFileByName(keyring) => Some(keyring),
+        }
+    }
+
+    /// With `FromFile` every invoker may authorise with the same key, so key doesn't identify invoker.
+    pub fn is_key_shared(&self) -> bool {
+        matches!(self, Self::FromFile(_))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::API(_) => "API",
+            Self::FromFile(_) => "FromFile",
+            Self::FromFileByName(_) => "FromFileByName",
+        }
+    }
+
+    /// With `API` method certificate is requested from every connected testing system until one of them knows the key, answers are cached according to `policy`.
+    pub async fn get_certificate_by_key(&self, key: &String, testing_systems: Vec<Arc<Mutex<TestingSystem>>>, policy: &CertCachePolicy) -> Result<Cert, String> {
+        Ok(match self {
+            Self::API(cert_cache) => cert_cache.get(key, testing_systems, policy).await?,
+            Self::FromFile(keyring) | Self::FromFileByName(keyring) => keyring.get(key).await?,
+        })
+    }
+}
@@ -0,0 +1,58 @@
+use std::{collections::BTreeMap, fs, path::PathBuf, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::server::json_file::{JsonFile, Snapshot};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revocation {
+    pub reason: Option<String>,
+    pub revoked_at: u64, // unix time in seconds
+}
+
+/// Keys which invokers can't authorise with, kept in JSON file `{<key>: <revocation>}`.
+pub struct Revocations {
+    file: JsonFile,
+    revoked: BTreeMap<String, Revocation>,
+}
+
+impl Revocations {
+    /// Missing file means that no key is revoked.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let path = path.into();
+        let revoked = if path.exists() {
+            let content = fs::read_to_string(&path)
+                .map_err(|err| format!("Can't read revocations {}: {err}", path.display()))?;
+            serde_json::from_str(&content)
+                .map_err(|err| format!("Can't parse revocations {}: {err}", path.display()))?
+        } else {
+            BTreeMap::new()
+        };
+        log::info!("revocations: Loaded | path = {} | revoked = {}", path.display(), revoked.len());
+        Ok(Self { file: JsonFile::new("revocations", path), revoked })
+    }
+
+    pub fn is_revoked(&self, key: &str) -> bool {
+        self.revoked.contains_key(key)
+    }
+
+    pub fn list(&self) -> &BTreeMap<String, Revocation> {
+        &self.revoked
+    }
+
+    /// Revocation is effective at once, returned snapshot should be saved after the server lock is released.
+    pub fn revoke(&mut self, key: &str, reason: Option<String>) -> Result<Snapshot, String> {
+        let revoked_at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+        self.revoked.insert(key.to_string(), Revocation { reason, revoked_at });
+        log::info!("revocations: Key revoked | key = {}", key);
+        self.file.snapshot(&self.revoked)
+    }
+
+    pub fn unrevoke(&mut self, key: &str) -> Result<Snapshot, String> {
+        if self.revoked.remove(key).is_none() {
+            return Err(format!("Key {key} isn't revoked"));
+        }
+        log::info!("revocations: Key unrevoked | key = {}", key);
+        self.file.snapshot(&self.revoked)
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+
+/// What invoker is able to run, it's declared in TOKEN message.
+/// Not declared `LANGS` or `ARCH` means that invoker accepts any, `FEATURES` have to be declared explicitly.
+#[derive(Debug, Clone, Default)]
+#[derive(Serialize)]
+pub struct Capabilities {
+    pub langs: HashSet<String>,
+    pub arch: Option<String>,
+    pub features: HashSet<String>,
+}
+
+/// What submission needs from invoker, it's sent by testing system in extended frame headers.
+#[derive(Debug, Clone, Default)]
+#[derive(Serialize, Deserialize)]
+pub struct Requirements {
+    pub lang: Option<String>,
+    pub arch: Option<String>,
+    pub features: Vec<String>,
+}
+
+fn words(value: Option<&String>) -> impl Iterator<Item = String> + '_ {
+    value.into_iter().flat_map(|value| value.split_whitespace()).map(|word| word.to_string())
+}
+
+impl Capabilities {
+    pub fn from_headers(headers: &HashMap<String, String>) -> Self {
+        Self {
+            langs: words(headers.get("LANGS")).collect(),
+            arch: headers.get("ARCH").map(|arch| arch.trim().to_string()).filter(|arch| !arch.is_empty()),
+            features: words(headers.get("FEATURES")).collect(),
+        }
+    }
+
+    pub fn satisfies(&self, requirements: &Requirements) -> bool {
+        let lang = match &requirements.lang {
+            Some(lang) => self.langs.is_empty() || self.langs.contains(lang),
+            None => true,
+        };
+        let arch = match (&requirements.arch, &self.arch) {
+            (Some(required), Some(arch)) => required == arch,
+            _ => true,
+        };
+        let features = requirements.features.iter().all(|feature| self.features.contains(feature));
+        lang && arch && features
+    }
+}
+
+impl Requirements {
+    pub fn from_headers(headers: &HashMap<String, String>) -> Self {
+        Self {
+            lang: headers.get("LANG").map(|lang| lang.trim().to_string()).filter(|lang| !lang.is_empty()),
+            arch: headers.get("ARCH").map(|arch| arch.trim().to_string()).filter(|arch| !arch.is_empty()),
+            features: words(headers.get("FEATURES")).collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for Requirements {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LANG {} ARCH {} FEATURES [{}]", self.lang.as_deref().unwrap_or("*"), self.arch.as_deref().unwrap_or("*"), self.features.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requirements(lang: Option<&str>, arch: Option<&str>, features: &[&str]) -> Requirements {
+        Requirements {
+            lang: lang.map(str::to_string),
+            arch: arch.map(str::to_string),
+            features: features.iter().map(|feature| feature.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn invoker_without_declarations_runs_any_lang_and_arch() {
+        let capabilities = Capabilities::default();
+        assert!(capabilities.satisfies(&requirements(Some("cpp"), Some("arm64"), &[])));
+        assert!(!capabilities.satisfies(&requirements(None, None, &["gpu"])));
+    }
+
+    #[test]
+    fn declared_capabilities_are_matched() {
+        let headers = HashMap::from([
+            ("LANGS".to_string(), "cpp python".to_string()),
+            ("ARCH".to_string(), " x86_64 ".to_string()),
+            ("FEATURES".to_string(), "gpu net".to_string()),
+        ]);
+        let capabilities = Capabilities::from_headers(&headers);
+        assert!(capabilities.satisfies(&requirements(Some("python"), Some("x86_64"), &["net", "gpu"])));
+        assert!(capabilities.satisfies(&Requirements::default()));
+        assert!(!capabilities.satisfies(&requirements(Some("rust"), None, &[])));
+        assert!(!capabilities.satisfies(&requirements(None, Some("arm64"), &[])));
+        assert!(!capabilities.satisfies(&requirements(None, None, &["gpu", "tpu"])));
+    }
+}
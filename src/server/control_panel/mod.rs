@@ -1,11 +1,16 @@
+pub mod tokens;
+
 use std::sync::Arc;
 
 use tokio::{net::TcpListener, sync::Mutex};
 use uuid::Uuid;
-use std::collections::HashMap;
-use axum::{extract::{State, Path}, response::IntoResponse, routing::{get, delete}, Router};
+use std::collections::{BTreeMap, HashMap};
+use axum::{extract::{State, Path, Query}, Json, http::{header, StatusCode}, middleware, response::{sse::{self, KeepAlive, Sse}, IntoResponse, Response}, routing::{get, delete, put}, Router};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+use serde::{Deserialize, Serialize};
 
-use super::{invokers_side::InvokersSide, verdict::TestResult, Server};
+use tokens::Tokens;
+use super::{authorisation::keyring::LoadedKey, invokers_registry::Identity, invoker::{Invoker, InvokerStatus}, invokers_side::InvokersSide, metrics::Snapshot, submissions_pool::QueuedSubmission, verdict::TestResult, Server};
 
 pub struct ControlPanel {
     listener: TcpListener,
@@ -13,10 +18,16 @@ pub struct ControlPanel {
 }
 
 impl ControlPanel {
-    pub async fn binded_to(ip: &str, server: Arc<Mutex<Server>>) -> Result<Self, String> {
-        let app = Router::new()
-            .nest("/control-panel", control_panel_handler())
-            .with_state(server);
+    /// Without `tokens` every request is allowed, configuration allows it only with `control_panel.insecure`.
+    pub async fn binded_to(ip: &str, server: Arc<Mutex<Server>>, tokens: Option<Tokens>) -> Result<Self, String> {
+        let mut app = Router::new()
+            .route("/metrics", get(get_metrics_handler))
+            .nest("/control-panel", control_panel_handler());
+        match tokens {
+            Some(tokens) => app = app.layer(middleware::from_fn_with_state(Arc::new(tokens), tokens::require_token)),
+            None => log::warn!("control_panel: Control panel is insecure, it's open for everyone"),
+        }
+        let app = app.with_state(server);
 
         Ok(Self {
             app,
@@ -36,17 +47,22 @@ impl ControlPanel {
     }
 }
 
+/// Error response with JSON body `{"error": <message>}`.
+fn error(status: StatusCode, message: &str) -> Response {
+    let body = serde_json::json!({ "error": message }).to_string();
+    let mut response = (status, [(header::CONTENT_TYPE, "application/json")], body).into_response();
+    if status == StatusCode::UNAUTHORIZED {
+        response.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+    }
+    response
+}
+
 async fn get_invokers_status_handler(State(server): State<Arc<Mutex<Server>>>) -> impl IntoResponse {
     log::trace!("Recieved GET invokers-status.");
     let invokers_status = server.lock().await.invokers_side.get_invokers_status().await;
-    let map: HashMap<String, Option<String>> = invokers_status.iter().map(
-        |(key, val)| (
-        key.to_string(),
-        if let Some(id) = val {
-            Some(id.to_string())
-        } else {
-            None
-        })
+    let map: HashMap<String, InvokerStatus> = invokers_status.iter().map(
+        |(key, val)|
+        (key.to_string(), val.clone())
     ).collect();
     
 
@@ -62,12 +78,28 @@ async fn get_invokers_status_handler(State(server): State<Arc<Mutex<Server>>>) -
     }
 }
 
+async fn get_testing_systems_status_handler(State(server): State<Arc<Mutex<Server>>>) -> impl IntoResponse {
+    log::trace!("Recieved GET testing-systems-status.");
+    let testing_systems_status = server.lock().await.testing_system_side.get_testing_systems_status().await;
+
+    match serde_json::to_string(&testing_systems_status) {
+        Ok(string) => {
+            log::trace!("Sending testing-systems-status: {string}");
+            string
+        },
+        Err(err) => {
+            log::error!("Failed to parse map of testing systems {testing_systems_status:?} to string: {err:?}");
+            "SERVERERROR".to_string()
+        }
+    }
+}
+
 async fn get_tests_results_handler(State(server): State<Arc<Mutex<Server>>>) -> impl IntoResponse {
     let tests_results = server.lock().await.tests_results.clone();
-    let map: HashMap<String, Vec<TestResult>> = tests_results.iter().map(
-        |(key, val)| 
-        (key.to_string(), val.clone())
-    ).collect();
+    let mut map: HashMap<String, HashMap<String, Vec<TestResult>>> = HashMap::new();
+    for ((origin, uuid), val) in tests_results {
+        map.entry(origin).or_default().insert(uuid.to_string(), val);
+    }
  
     match serde_json::to_string(&map) {
         Ok(string) => {
@@ -81,23 +113,270 @@ async fn get_tests_results_handler(State(server): State<Arc<Mutex<Server>>>) ->
     }   
 }
 
-async fn delete_invoker_handler(Path(path): Path<String>, State(server): State<Arc<Mutex<Server>>>) -> impl IntoResponse {
-    let invoker_uuid = match Uuid::try_from(path) {
+async fn get_queue_priorities_handler(State(server): State<Arc<Mutex<Server>>>) -> impl IntoResponse {
+    let submissions_pool = server.lock().await.submissions_pool.clone();
+    let map: HashMap<String, usize> = submissions_pool.priority_distribution().await.iter().map(
+        |(priority, count)|
+        (priority.to_string(), *count)
+    ).collect();
+
+    match serde_json::to_string(&map) {
+        Ok(string) => {
+            log::trace!("Sending queue-priorities {string}");
+            string
+        },
+        Err(err) => {
+            log::error!("Failed to parse map of queue priorities {map:?} to string: {err:?}");
+            "SERVERERROR".to_string()
+        }
+    }
+}
+
+async fn get_metrics_handler(State(server): State<Arc<Mutex<Server>>>) -> impl IntoResponse {
+    let (metrics, submissions_pool, invokers) = {
+        let server_locked = server.lock().await;
+        let invokers: Vec<Arc<Mutex<Invoker>>> = server_locked.invokers_side.invokers.values().cloned().collect();
+        (server_locked.metrics.clone(), server_locked.submissions_pool.clone(), invokers)
+    };
+    let mut invokers_busy = 0;
+    for invoker in invokers.iter() {
+        if invoker.lock().await.get_submission_uuid().is_some() {
+            invokers_busy += 1;
+        }
+    }
+    let snapshot = Snapshot {
+        queue_depth: submissions_pool.len().await,
+        invokers_connected: invokers.len(),
+        invokers_busy,
+    };
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics.render(snapshot))
+}
+
+#[derive(Serialize)]
+struct Queue {
+    total: usize,
+    submissions: Vec<QueuedSubmission>,
+}
+
+async fn get_queue_handler(State(server): State<Arc<Mutex<Server>>>) -> impl IntoResponse {
+    let submissions_pool = server.lock().await.submissions_pool.clone();
+    let submissions = submissions_pool.snapshot().await;
+    let queue = Queue {
+        total: submissions.len(),
+        submissions,
+    };
+
+    match serde_json::to_string(&queue) {
+        Ok(string) => {
+            log::trace!("Sending queue {string}");
+            string
+        },
+        Err(err) => {
+            log::error!("Failed to parse queue to string: {err:?}");
+            "SERVERERROR".to_string()
+        }
+    }
+}
+
+async fn get_events_handler(State(server): State<Arc<Mutex<Server>>>) -> impl IntoResponse {
+    log::trace!("New subscriber of events.");
+    let events = BroadcastStream::new(server.lock().await.events.subscribe()).filter_map(|event| match event {
+        Ok(event) => match sse::Event::default().json_data(&event) {
+            Ok(event) => Some(Ok::<_, std::convert::Infallible>(event)),
+            Err(err) => {
+                log::error!("Failed to serialize event {event:?}: {err:?}");
+                None
+            }
+        },
+        Err(err) => {
+            log::warn!("Events subscriber is lagging behind: {err:?}");
+            None
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[derive(Serialize)]
+struct KnownInvoker {
+    #[serde(flatten)]
+    identity: Identity,
+    online: bool,
+    status: Option<InvokerStatus>,
+}
+
+async fn get_invokers_handler(State(server): State<Arc<Mutex<Server>>>) -> impl IntoResponse {
+    let (identities, invokers_status) = {
+        let server_locked = server.lock().await;
+        (server_locked.invokers_registry.list().clone(), server_locked.invokers_side.get_invokers_status().await)
+    };
+    let invokers: Vec<KnownInvoker> = identities.into_values().map(|identity| {
+        let status = invokers_status.get(&identity.uuid).cloned();
+        KnownInvoker {
+            identity,
+            online: status.is_some(),
+            status,
+        }
+    }).collect();
+
+    match serde_json::to_string(&invokers) {
+        Ok(string) => {
+            log::trace!("Sending invokers {string}");
+            string
+        },
+        Err(err) => {
+            log::error!("Failed to parse invokers to string: {err:?}");
+            "SERVERERROR".to_string()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct UpdateInvokerBody {
+    name: Option<String>,
+    labels: Option<BTreeMap<String, String>>,
+}
+
+async fn update_invoker_handler(Path(path): Path<String>, State(server): State<Arc<Mutex<Server>>>, Json(body): Json<UpdateInvokerBody>) -> Response {
+    let invoker_uuid = match Uuid::try_from(path.as_str()) {
+        Ok(invoker_uuid) => invoker_uuid,
+        Err(_err) => {
+            return error(StatusCode::BAD_REQUEST, &format!("{path} doesn't parse to uuid."));
+        }
+    };
+
+    let snapshot = server.lock().await.invokers_registry.update(invoker_uuid, body.name, body.labels);
+    let saved = match snapshot {
+        Ok(snapshot) => snapshot.save().await,
+        Err(err) => return error(StatusCode::NOT_FOUND, &err),
+    };
+    if let Err(err) = saved {
+        log::error!("control_panel: Couldn't save invokers registry | error = {}", err);
+        return error(StatusCode::INTERNAL_SERVER_ERROR, &err);
+    }
+    "Succes".into_response()
+}
+
+#[derive(Deserialize)]
+struct DeleteInvokerQuery {
+    #[serde(default)]
+    force: bool,
+}
+
+async fn delete_invoker_handler(Path(path): Path<String>, Query(query): Query<DeleteInvokerQuery>, State(server): State<Arc<Mutex<Server>>>) -> Response {
+    let invoker_uuid = match Uuid::try_from(path.as_str()) {
         Ok(invoker_uuid) => invoker_uuid,
         Err(_err) => {
-            return "{invoker_uuid} doesn't parse to uuid.".to_string();
+            return error(StatusCode::BAD_REQUEST, &format!("{path} doesn't parse to uuid."));
+        }
+    };
+
+    if let Err(err) = InvokersSide::delete_invoker(server, invoker_uuid, query.force).await {
+        return error(StatusCode::NOT_FOUND, &err);
+    }
+    "Succes".into_response()
+}
+
+#[derive(Deserialize)]
+struct DeleteSubmissionQuery {
+    origin: Option<String>, // without it submission with this uuid from any testing system is cancelled
+}
+
+async fn delete_submission_handler(Path(path): Path<String>, Query(query): Query<DeleteSubmissionQuery>, State(server): State<Arc<Mutex<Server>>>) -> Response {
+    let submission_uuid = match Uuid::try_from(path.as_str()) {
+        Ok(submission_uuid) => submission_uuid,
+        Err(_err) => {
+            return error(StatusCode::BAD_REQUEST, &format!("{path} doesn't parse to uuid."));
         }
     };
 
-    if let Err(err) = InvokersSide::delete_invoker(server, invoker_uuid).await {
-        return err;
+    if let Err(err) = Server::cancel_submission(server, query.origin.as_deref(), submission_uuid).await {
+        return error(StatusCode::NOT_FOUND, &err);
+    }
+    "Succes".into_response()
+}
+
+#[derive(Serialize)]
+struct Keys {
+    method: &'static str,
+    keys: Vec<LoadedKey>,
+}
+
+async fn get_keys_handler(State(server): State<Arc<Mutex<Server>>>) -> impl IntoResponse {
+    let authorisation = server.lock().await.authorisation.clone();
+    let keys = Keys {
+        method: authorisation.name(),
+        keys: match authorisation.keyring() {
+            Some(keyring) => keyring.list().await,
+            None => Vec::new(),
+        },
+    };
+
+    match serde_json::to_string(&keys) {
+        Ok(string) => {
+            log::trace!("Sending keys {string}");
+            string
+        },
+        Err(err) => {
+            log::error!("Failed to parse keys to string: {err:?}");
+            "SERVERERROR".to_string()
+        }
+    }
+}
+
+async fn get_revocations_handler(State(server): State<Arc<Mutex<Server>>>) -> impl IntoResponse {
+    let revocations = server.lock().await.revocations.list().clone();
+
+    match serde_json::to_string(&revocations) {
+        Ok(string) => {
+            log::trace!("Sending revocations {string}");
+            string
+        },
+        Err(err) => {
+            log::error!("Failed to parse revocations {revocations:?} to string: {err:?}");
+            "SERVERERROR".to_string()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RevokeQuery {
+    reason: Option<String>,
+}
+
+async fn revoke_key_handler(Path(key): Path<String>, Query(query): Query<RevokeQuery>, State(server): State<Arc<Mutex<Server>>>) -> Response {
+    // invoker chooses key it declares, with shared certificate any key is accepted, so revoking one doesn't keep anybody out
+    if server.lock().await.authorisation.is_key_shared() {
+        return error(StatusCode::CONFLICT, "Keys can't be revoked while every invoker authorises with the same certificate");
+    }
+    match InvokersSide::revoke_key(server, &key, query.reason).await {
+        Ok(disconnected) => format!("Succes, disconnected {disconnected} invokers").into_response(),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, &err),
+    }
+}
+
+async fn unrevoke_key_handler(Path(key): Path<String>, State(server): State<Arc<Mutex<Server>>>) -> Response {
+    let snapshot = match server.lock().await.revocations.unrevoke(&key) {
+        Ok(snapshot) => snapshot,
+        Err(err) => return error(StatusCode::NOT_FOUND, &err),
+    };
+    if let Err(err) = snapshot.save().await {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, &format!("Key is unrevoked until restart, but revocations couldn't be saved: {err}"));
     }
-    "Succes".to_string()
+    "Succes".into_response()
 }
 
 fn control_panel_handler() -> Router<Arc<Mutex<Server>>> {
     Router::<Arc<Mutex<Server>>>::new()
         .route("/invokers-status", get(get_invokers_status_handler))
+        .route("/testing-systems-status", get(get_testing_systems_status_handler))
         .route("/tests-results", get(get_tests_results_handler))
-        .route("/invokers/{invoker_uuid}", delete(delete_invoker_handler))
+        .route("/queue", get(get_queue_handler))
+        .route("/queue-priorities", get(get_queue_priorities_handler))
+        .route("/events", get(get_events_handler))
+        .route("/keys", get(get_keys_handler))
+        .route("/revocations", get(get_revocations_handler))
+        .route("/revocations/{key}", put(revoke_key_handler).delete(unrevoke_key_handler))
+        .route("/invokers", get(get_invokers_handler))
+        .route("/invokers/{invoker_uuid}", delete(delete_invoker_handler).put(update_invoker_handler))
+        .route("/submissions/{submission_uuid}", delete(delete_submission_handler))
 }
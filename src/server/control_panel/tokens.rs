@@ -0,0 +1,105 @@
+use std::{fs, path::Path, sync::Arc};
+
+use axum::{extract::{Request, State}, http::{header, Method, StatusCode}, middleware::Next, response::Response};
+
+use super::error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Read, // GET requests: status, results, metrics
+    Admin, // everything, including deleting invokers and cancelling submissions
+}
+
+/// Bearer tokens of control panel users.
+/// File format: one `<role> <token>` pair per line, role is `read` or `admin`, lines starting with `#` are ignored.
+pub struct Tokens {
+    roles: Vec<(String, Role)>,
+}
+
+impl Tokens {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("Can't read control panel tokens {}: {err}", path.display()))?;
+        let mut roles = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((role, token)) = line.split_once(char::is_whitespace) else {
+                return Err(format!("Line {} of {} should be `<role> <token>`", number + 1, path.display()));
+            };
+            let role = match role {
+                "read" => Role::Read,
+                "admin" => Role::Admin,
+                _ => return Err(format!("Unknown role {role} on line {} of {}", number + 1, path.display())),
+            };
+            roles.push((token.trim().to_string(), role));
+        }
+        log::info!("control_panel: Tokens loaded | path = {} | count = {}", path.display(), roles.len());
+        Ok(Self { roles })
+    }
+
+    fn role_of(&self, request: &Request) -> Option<Role> {
+        let token = request.headers().get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ")?;
+        let token = token.trim().as_bytes();
+        // every token is compared, so time of the check doesn't tell which one is close to the given one
+        self.roles.iter().fold(None, |found, (known, role)| if constant_time_eq(known.as_bytes(), token) { Some(*role) } else { found })
+    }
+}
+
+/// Time of comparison depends only on lengths of tokens, not on their content.
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    if left.len() != right.len() {
+        return false;
+    }
+    left.iter().zip(right).fold(0u8, |difference, (left, right)| difference | (left ^ right)) == 0
+}
+
+/// Lets through GET requests with any known token and other requests only with admin token.
+pub async fn require_token(State(tokens): State<Arc<Tokens>>, request: Request, next: Next) -> Response {
+    let Some(role) = tokens.role_of(&request) else {
+        log::warn!("control_panel: Request without valid token | method = {} | uri = {}", request.method(), request.uri());
+        return error(StatusCode::UNAUTHORIZED, "Missing or unknown bearer token");
+    };
+    let required = if request.method() == Method::GET { Role::Read } else { Role::Admin };
+    if role < required {
+        log::warn!("control_panel: Request with insufficient role | method = {} | uri = {} | role = {:?}", request.method(), request.uri(), role);
+        return error(StatusCode::FORBIDDEN, "Token doesn't allow this request");
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, content: &str) -> Result<Tokens, String> {
+        let path = std::env::temp_dir().join(format!("invoker-manager-{}-{name}.tokens", std::process::id()));
+        fs::write(&path, content).unwrap();
+        let tokens = Tokens::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        tokens
+    }
+
+    fn role(tokens: &Tokens, authorization: &str) -> Option<Role> {
+        let request = Request::builder().header(header::AUTHORIZATION, authorization).body(axum::body::Body::empty()).unwrap();
+        tokens.role_of(&request)
+    }
+
+    #[test]
+    fn roles_are_given_by_tokens_file() {
+        let tokens = load("valid", "# operators\nread viewer-token\n\nadmin   admin-token  \n").unwrap();
+        assert_eq!(role(&tokens, "Bearer admin-token"), Some(Role::Admin));
+        assert_eq!(role(&tokens, "Bearer viewer-token"), Some(Role::Read));
+        assert_eq!(role(&tokens, "Bearer viewer-toke"), None);
+        assert_eq!(role(&tokens, "admin-token"), None);
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        assert!(load("no-token", "admin\n").err().unwrap().starts_with("Line 1 of"));
+        assert!(load("unknown-role", "read token\nroot token\n").err().unwrap().starts_with("Unknown role root on line 2"));
+    }
+}
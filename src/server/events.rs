@@ -0,0 +1,40 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+use super::verdict::{TestResult, Verdict};
+
+const EVENTS_CAPACITY: usize = 1024; // subscribers which fall behind more than this lose the oldest events
+
+/// Event of submission's or invoker's lifecycle, published for control panel subscribers.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    SubmissionQueued { submission_uuid: Uuid, tests_count: u16, priority: i32, attempts: u8 },
+    SubmissionAssigned { submission_uuid: Uuid, invoker_uuid: Uuid },
+    TestVerdict { submission_uuid: Uuid, test: u16, result: TestResult },
+    SubmissionVerdict { submission_uuid: Uuid, verdict: Verdict },
+    InvokerConnected { invoker_uuid: Uuid },
+    InvokerAuthorised { invoker_uuid: Uuid },
+    InvokerDisconnected { invoker_uuid: Uuid },
+}
+
+/// Broadcasts events to every subscriber, events are dropped if nobody is subscribed.
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(EVENTS_CAPACITY).0,
+        }
+    }
+
+    pub fn publish(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
@@ -3,10 +3,10 @@ use uuid::Uuid;
 use bytes::BytesMut;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use std::{collections::HashMap, str::{self, FromStr}};
+use std::{collections::HashMap, str};
 use super::{WSReader, WSWriter, Invoker};
-use ratchet_rs::Error;
-use crate::server::{submission::Submission, verdict::{TestResult, Verdict}};
+use ratchet_rs::Message;
+use crate::server::{capabilities::Capabilities, protocol::{self, ProtocolError}, submission::Submission, verdict::{GroupScore, Score, TestResult, Verdict}};
 
 pub struct Gateway;
 
@@ -16,10 +16,17 @@ impl Gateway {
         Ok(())
     }
 
-    async fn read_data_from(socket: &mut WSReader) -> Result<Vec<u8>, Error> {
-         let mut bin = BytesMut::new();
-        socket.read(&mut bin).await?;
-        Ok(bin.to_vec())
+    /// Reads next data frame, `None` means that pong was received. Close frame ends the stream, it isn't a message.
+    async fn read_data_from(socket: &mut WSReader) -> Result<Option<Vec<u8>>, String> {
+        loop {
+            let mut bin = BytesMut::new();
+            match socket.read(&mut bin).await.map_err(|err| err.to_string())? {
+                Message::Pong(_) => return Ok(None),
+                Message::Ping(_) => continue,
+                Message::Close(_) => return Err("Invoker closed connection".to_string()),
+                _ => return Ok(Some(bin.to_vec())),
+            }
+        }
     }
 
     fn first_line_of_bytes(data: &[u8]) -> (String, String, &[u8]) {
@@ -31,16 +38,21 @@ impl Gateway {
         (message_type.to_string(), first_line_arguments.to_string(), data)
     }
 
-    pub async fn read_message_from(socket: &mut WSReader) -> Result<InputMessage, String> {
-        let Ok(data) = Self::read_data_from(socket).await else {
-            log::error!("Couldn't read data from socket");
+    /// Outer error means that connection is broken, inner one means that invoker sent malformed message.
+    pub async fn read_message_from(socket: &mut WSReader) -> Result<Result<InputMessage, ProtocolError>, String> {
+        let data = match Self::read_data_from(socket).await {
+            Ok(data) => data,
+            Err(err) => {
+                log::info!("Stopped reading data from socket | reason = {}", err);
 
-            return Err("Couldn't read data from socket".to_string());
+                return Err(err);
+            }
+        };
+        let Some(data) = data else {
+            return Ok(Ok(InputMessage::Pong));
         };
         log::info!("Data readed from socket");
-        let message = data.try_into()?;
-        log::info!("Data from socket parsed");
-        Ok(message)
+        Ok(data.try_into())
     }
 
     pub fn parse_headers(bytes: Vec<u8>) -> (HashMap<String, String>, Vec<u8>) {
@@ -72,6 +84,35 @@ impl Gateway {
         Ok(())
     }
 
+    pub async fn send_stop(invoker: Arc<Mutex<Invoker>>) -> Result<(), String> {
+        let writer_unlocked = invoker.lock().await.writer.clone();
+        let mut writer = writer_unlocked.lock().await;
+        Self::send_message_to(&mut writer, OutputMessage::StopTesting).await?;
+        Ok(())
+    }
+
+    pub async fn send_close(invoker: Arc<Mutex<Invoker>>) -> Result<(), String> {
+        let writer_unlocked = invoker.lock().await.writer.clone();
+        let mut writer = writer_unlocked.lock().await;
+        Self::send_message_to(&mut writer, OutputMessage::CloseInvoker).await?;
+        Ok(())
+    }
+
+    pub async fn send_protocol_error(invoker: Arc<Mutex<Invoker>>, error: &ProtocolError) -> Result<(), String> {
+        let writer_unlocked = invoker.lock().await.writer.clone();
+        let mut writer = writer_unlocked.lock().await;
+        Self::send_message_to(&mut writer, OutputMessage::ProtocolError {
+            message: error.to_string(),
+        }).await?;
+        Ok(())
+    }
+
+    pub async fn send_ping(invoker: Arc<Mutex<Invoker>>) -> Result<(), String> {
+        let writer_unlocked = invoker.lock().await.writer.clone();
+        let mut writer = writer_unlocked.lock().await;
+        writer.write_ping([0u8; 0]).await.map_err(|err| err.to_string())
+    }
+
     pub async fn send_auth_challenge(invoker: Arc<Mutex<Invoker>>, challenge: &Challenge) -> Result<(), String> {
         let writer_unlocked = invoker.lock().await.writer.clone();
         let mut writer = writer_unlocked.lock().await;
@@ -80,20 +121,43 @@ impl Gateway {
     }
 }
 
+/// Submission and nonce which invoker got in START, it echoes them in every message about the submission.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Assignment {
+    pub submission_uuid: Uuid,
+    pub nonce: u64,
+}
+
+impl Assignment {
+    /// Messages of `invoker.v1` don't contain assignment, but if one of headers is sent both have to be valid.
+    fn from_headers(headers: &HashMap<String, String>) -> Result<Option<Self>, ProtocolError> {
+        if !headers.contains_key("SUBMISSION") && !headers.contains_key("NONCE") {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            submission_uuid: protocol::optional_uuid(headers, "SUBMISSION")?.ok_or(ProtocolError::MissingHeader("SUBMISSION"))?,
+            nonce: protocol::number(headers, "NONCE")?,
+        }))
+    }
+}
+
 #[derive(Debug)]
 pub enum InputMessage {
     SignedChallenge {
         bytes: Box<[u8]>,
     },
     Token {
-        uuid: Uuid,
+        uuid: Option<Uuid>, // declared by invoker, real one is taken from invokers registry
         key: String,
+        capabilities: Capabilities,
     },
     Verdict {
+        assignment: Option<Assignment>,
         verdict: Verdict,
-        message: Result<(u8, Vec<u8>), String>,
+        message: Result<Score, String>,
     },
     TestVerdict {
+        assignment: Option<Assignment>,
         result: TestResult,
         test: u16,
         data: Vec<u8>,
@@ -106,70 +170,80 @@ pub enum InputMessage {
         message: String,
     },
     OpError { // don't parsed
+        assignment: Option<Assignment>,
         message: String,
     },
+    Pong,
 }
 
 pub enum OutputMessage {
     TestSubmission {
         submission: Submission,
+        nonce: Option<u64>, // only for `invoker.v2`
     },
     AuthVerdict {
         verdict: bool,
     },
     Challenge(Box<[u8]>),
-    _StopTesting,
-    _CloseInvoker,
+    StopTesting,
+    CloseInvoker,
+    ProtocolError {
+        message: String,
+    },
 }
 
 impl TryFrom<Vec<u8>> for InputMessage {
-    type Error = String;
-    fn try_from(bytes: Vec<u8>) -> Result<Self, String> {
+    type Error = ProtocolError;
+    fn try_from(bytes: Vec<u8>) -> Result<Self, ProtocolError> {
         let (headers, data) = Gateway::parse_headers(bytes);
-        let Some(message_type) = headers.get("TYPE") else {
-            log::error!("Message doesn't contain TYPE header");
-
-            return Err("Message doesn't contain TYPE header".to_string());
-        };
-        match message_type.as_str() {
+        let message_type = protocol::header(&headers, "TYPE")?;
+        match message_type {
             "TOKEN" => {
-                let uuid = Uuid::from_str(headers.get("ID").map_or("", |s| s)).unwrap_or(Uuid::from_bytes(rand::random::<[u8; 16]>()));
-                let key = headers.get("KEY").map_or("", |s| s).to_string();
                 Ok(InputMessage::Token{
-                    uuid,
-                    key,
+                    uuid: protocol::optional_uuid(&headers, "ID")?,
+                    key: protocol::header(&headers, "KEY")?.to_string(),
+                    capabilities: Capabilities::from_headers(&headers),
                 })
             },
             "VERDICT" => {
-                let verdict = Verdict::from(headers.get("NAME").unwrap_or(&"UV".to_string()));
-                if let Verdict::UV = verdict {
-                    log::error!("Readed UV verdict | headers = {:?}", headers);
-                }
+                let assignment = Assignment::from_headers(&headers)?;
+                let verdict = protocol::verdict(&headers, "NAME")?;
                 if let Verdict::OK = verdict {
-                    let sum = u8::from_str(headers.get("SUM").map_or("0", |v| v)).unwrap_or(0);
-                    let points = headers.get("GROUPS").unwrap_or(&"0".to_string()).split(" ").map(|string| u8::from_str(string).unwrap_or(0)).collect();
+                    let sum = protocol::points(&headers, "SUM")?;
+                    let points = protocol::groups_points(&headers, "GROUPS")?;
+                    let max_points = protocol::optional_groups_points(&headers, "GROUPS_MAX", points.len())?;
+                    let verdicts = protocol::optional_groups_verdicts(&headers, "GROUPS_VERDICTS", points.len())?;
+                    protocol::check_score(sum, &points, max_points.as_deref())?;
+                    let groups = points.into_iter().enumerate().map(|(group, points)| GroupScore {
+                        points,
+                        max_points: max_points.as_ref().map(|max_points| max_points[group]),
+                        verdict: verdicts.as_ref().map(|verdicts| verdicts[group].clone()),
+                    }).collect();
                     Ok(InputMessage::Verdict {
+                        assignment,
                         verdict,
-                        message: Ok((sum, points)),
+                        message: Ok(Score { sum, groups }),
                     })
                 } else {
                     let message = headers.get("MESSAGE").cloned().unwrap_or("Undefined error message".to_string());
                     Ok(InputMessage::Verdict {
+                        assignment,
                         verdict,
                         message: Err(message),
                     })
                 }
             },
             "TEST" => {
-                let test: u16 = headers.get("ID").map_or(1, |v| u16::from_str(v).unwrap_or(1));
-                let verdict = Verdict::from(headers.get("VERDICT").unwrap_or(&"UV".to_string()));
-                let time: f32 = headers.get("TIME").map_or(0.0, |v| f32::from_str(v).unwrap_or(0.0));
-                let memory: u32 = headers.get("MEMORY").map_or(0, |v| u32::from_str(v).unwrap_or(0));
+                let test: u16 = protocol::number(&headers, "ID")?;
+                if test == 0 {
+                    return Err(ProtocolError::BadNumber { header: "ID", value: test.to_string() }); // tests are numbered from 1
+                }
                 Ok(InputMessage::TestVerdict {
+                    assignment: Assignment::from_headers(&headers)?,
                     result: TestResult {
-                        verdict,
-                        time,
-                        memory,
+                        verdict: protocol::verdict(&headers, "VERDICT")?,
+                        time: protocol::number(&headers, "TIME")?,
+                        memory: protocol::number(&headers, "MEMORY")?,
                     },
                     test,
                     data,
@@ -192,6 +266,7 @@ impl TryFrom<Vec<u8>> for InputMessage {
             "OPERROR" => {
                 let operror = headers.get("MESSAGE").cloned().unwrap_or("".to_string());
                 Ok(InputMessage::OpError{
+                    assignment: Assignment::from_headers(&headers)?,
                     message: operror
                 })
             },
@@ -200,7 +275,7 @@ impl TryFrom<Vec<u8>> for InputMessage {
                     bytes: data.into(),
                 })
             },
-            &_ => Err("Can't parse message".to_string())
+            message_type => Err(ProtocolError::UnknownType(message_type.to_string())),
         }
     }
 }
@@ -208,19 +283,26 @@ impl TryFrom<Vec<u8>> for InputMessage {
 impl Into<Vec<u8>> for OutputMessage {
     fn into(self) -> Vec<u8> {
         match self {
-            Self::TestSubmission { submission } => {
-                let mut result = "TYPE START\nDATA\n".as_bytes().to_vec();
+            Self::TestSubmission { submission, nonce } => {
+                let mut result = match nonce {
+                    Some(nonce) => format!("TYPE START\nSUBMISSION {}\nNONCE {}\nDATA\n", submission.uuid, nonce).as_bytes().to_vec(),
+                    None => "TYPE START\nDATA\n".as_bytes().to_vec(),
+                };
                 result.append(&mut submission.data.clone());
                 result
             },
-            Self::_StopTesting => {
+            Self::StopTesting => {
                 let result = "TYPE STOP\n".as_bytes().to_vec();
                 result
             },
-            Self::_CloseInvoker => {
+            Self::CloseInvoker => {
                 let result = "TYPE CLOSE\n".as_bytes().to_vec();
                 result
             },
+            Self::ProtocolError { message } => {
+                let result = format!("TYPE PROTOCOL_ERROR\nMESSAGE {}\n", message).as_bytes().to_vec();
+                result
+            },
             Self::Challenge ( bytes ) => {
                 let mut result = "TYPE AUTH_CHALLENGE\nDATA\n".as_bytes().to_vec();
                 result.append(&mut bytes.to_vec());
@@ -239,3 +321,68 @@ impl Into<Vec<u8>> for OutputMessage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(message: &str) -> Result<InputMessage, ProtocolError> {
+        message.as_bytes().to_vec().try_into()
+    }
+
+    #[test]
+    fn token_declares_capabilities() {
+        let Ok(InputMessage::Token { uuid, key, capabilities }) = parse("TYPE TOKEN\nID 67e55044-10b1-426f-9247-bb680e5fe0c8\nKEY judge\nLANGS cpp python\nARCH x86_64\n") else {
+            panic!("TOKEN isn't parsed");
+        };
+        assert_eq!(uuid, Some(Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap()));
+        assert_eq!(key, "judge");
+        assert!(capabilities.langs.contains("python"));
+        assert_eq!(capabilities.arch.as_deref(), Some("x86_64"));
+        assert_eq!(parse("TYPE TOKEN\nID judge\nKEY judge\n").unwrap_err(), ProtocolError::BadUuid { header: "ID", value: "judge".to_string() });
+        assert_eq!(parse("TYPE TOKEN\n").unwrap_err(), ProtocolError::MissingHeader("KEY"));
+    }
+
+    #[test]
+    fn verdict_carries_score_and_assignment() {
+        let Ok(InputMessage::Verdict { assignment, verdict: Verdict::OK, message: Ok(score) }) = parse("TYPE VERDICT\nSUBMISSION 67e55044-10b1-426f-9247-bb680e5fe0c8\nNONCE 3\nNAME OK\nSUM 30\nGROUPS 10 20\nGROUPS_MAX 10 20\nGROUPS_VERDICTS OK OK\n") else {
+            panic!("VERDICT isn't parsed");
+        };
+        assert_eq!(assignment, Some(Assignment { submission_uuid: Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap(), nonce: 3 }));
+        assert_eq!(score.sum, 30.0);
+        assert_eq!(score.groups.iter().map(|group| group.max_points).collect::<Vec<_>>(), vec![Some(10.0), Some(20.0)]);
+        let Ok(InputMessage::Verdict { assignment: None, verdict: Verdict::WA, message: Err(message) }) = parse("TYPE VERDICT\nNAME WA\nMESSAGE wrong answer\n") else {
+            panic!("VERDICT isn't parsed");
+        };
+        assert_eq!(message, "wrong answer");
+    }
+
+    #[test]
+    fn malformed_verdicts_are_rejected() {
+        assert_eq!(parse("TYPE VERDICT\nNAME NOPE\n").unwrap_err(), ProtocolError::UnknownVerdict("NOPE".to_string()));
+        assert_eq!(parse("TYPE VERDICT\nNAME OK\nSUM ten\nGROUPS 10\n").unwrap_err(), ProtocolError::BadNumber { header: "SUM", value: "ten".to_string() });
+        assert_eq!(parse("TYPE VERDICT\nNAME OK\nSUM 10\nGROUPS 5 5\nGROUPS_MAX 10\n").unwrap_err(), ProtocolError::GroupsMismatch { header: "GROUPS_MAX", expected: 2, got: 1 });
+        assert_eq!(parse("TYPE VERDICT\nNAME OK\nSUM 10\nGROUPS 5 5\nGROUPS_VERDICTS OK\n").unwrap_err(), ProtocolError::GroupsMismatch { header: "GROUPS_VERDICTS", expected: 2, got: 1 });
+        assert_eq!(parse("TYPE VERDICT\nNONCE 1\nNAME WA\n").unwrap_err(), ProtocolError::MissingHeader("SUBMISSION"));
+        assert!(matches!(parse("TYPE VERDICT\nNAME OK\nSUM 11\nGROUPS 5 5\n"), Err(ProtocolError::InconsistentScore(_))));
+        assert!(matches!(parse("TYPE VERDICT\nNAME OK\nSUM 10\nGROUPS 5 5\nGROUPS_MAX 10 4\n"), Err(ProtocolError::InconsistentScore(_))));
+    }
+
+    #[test]
+    fn test_verdict_is_checked() {
+        let Ok(InputMessage::TestVerdict { result, test: 2, data, .. }) = parse("TYPE TEST\nID 2\nVERDICT TL\nTIME 1000\nMEMORY 256\nDATA\noutput") else {
+            panic!("TEST isn't parsed");
+        };
+        assert!(matches!(result.verdict, Verdict::TL));
+        assert_eq!(data, b"output");
+        assert_eq!(parse("TYPE TEST\nID 0\nVERDICT OK\nTIME 1\nMEMORY 1\n").unwrap_err(), ProtocolError::BadNumber { header: "ID", value: "0".to_string() });
+        assert_eq!(parse("TYPE TEST\nID 1\nVERDICT OK\nTIME 1\nMEMORY 1kb\n").unwrap_err(), ProtocolError::BadNumber { header: "MEMORY", value: "1kb".to_string() });
+        assert_eq!(parse("TYPE TEST\nID 1\nVERDICT UV\nTIME 1\nMEMORY 1\n").unwrap_err(), ProtocolError::UnknownVerdict("UV".to_string()));
+    }
+
+    #[test]
+    fn unknown_types_are_rejected() {
+        assert_eq!(parse("TYPE DANCE\n").unwrap_err(), ProtocolError::UnknownType("DANCE".to_string()));
+        assert_eq!(parse("KEY judge\n").unwrap_err(), ProtocolError::MissingHeader("TYPE"));
+    }
+}
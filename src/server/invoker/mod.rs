@@ -1,49 +1,89 @@
 pub mod gateway;
 
-use std::sync::Arc;
+use std::{future::Future, pin::Pin, sync::Arc, time::{Duration, Instant}};
 
 use ratchet_deflate::{DeflateDecoder, DeflateEncoder};
 use ratchet_rs::{Receiver, Sender};
-use tokio::{net::TcpStream, sync::Mutex};
+use serde::Serialize;
+use tokio::sync::{watch, Mutex};
 use uuid::Uuid;
-pub use gateway::{Gateway, InputMessage, OutputMessage};
-use super::{testing_system, Server, submission::Submission};
+pub use gateway::{Assignment, Gateway, InputMessage, OutputMessage};
+use super::{capabilities::Capabilities, events::Event, protocol::{ErrorBudget, InvokerProtocol, ProtocolError}, tls::MaybeTlsStream, testing_system_side::TestingSystemSide, Server, submission::Submission, verdict::TestResult};
 use invoker_auth::{policy, Challenge, Solution};
 
-pub type WSReader = Receiver<TcpStream, DeflateDecoder>;
-pub type WSWriter = Sender<TcpStream, DeflateEncoder>;
+pub type WSReader = Receiver<MaybeTlsStream, DeflateDecoder>;
+pub type WSWriter = Sender<MaybeTlsStream, DeflateEncoder>;
 
 pub struct Invoker {
     uuid: Uuid,
     key: String,
+    identity: Option<String>, // entry of invokers registry, see `invokers_registry::identity_of`
+    protocol: InvokerProtocol,
+    capabilities: Capabilities,
     writer: Arc<Mutex<WSWriter>>,
     reader: Arc<Mutex<WSReader>>,
-    submission_uuid: Option<Uuid>,
+    submission: Option<Submission>,
+    draining: bool, // invoker doesn't take new submissions and is closed after finishing current one
+    closed: watch::Sender<bool>,
+    ping_sent_at: Option<Instant>,
+    last_pong_at: Instant,
+    rtt: Option<Duration>,
+    assignment: u64, // number of submissions given to invoker, identifies current assignment
+    deadline_misses: u32,
+    protocol_errors: ErrorBudget,
+}
+
+#[derive(Debug, Clone)]
+#[derive(Serialize)]
+pub struct InvokerStatus {
+    pub protocol: InvokerProtocol,
+    pub submission: Option<Uuid>,
+    pub draining: bool,
+    pub rtt: Option<f64>, // milliseconds
+    pub deadline_misses: u32,
+    pub malformed_messages: u64,
 }
 
 impl Invoker {
-    pub fn new(uuid: Uuid, key: String, reader: WSReader, writer: WSWriter) -> Self {
+    pub fn new(uuid: Uuid, key: String, identity: Option<String>, protocol: InvokerProtocol, capabilities: Capabilities, reader: WSReader, writer: WSWriter) -> Self {
         Self {
             uuid,
             key,
+            identity,
+            protocol,
+            capabilities,
             writer: Arc::new(Mutex::new(writer)),
             reader: Arc::new(Mutex::new(reader)),
-            submission_uuid: None,
+            submission: None,
+            draining: false,
+            closed: watch::channel(false).0,
+            ping_sent_at: None,
+            last_pong_at: Instant::now(),
+            rtt: None,
+            assignment: 0,
+            deadline_misses: 0,
+            protocol_errors: ErrorBudget::default(),
         }
     }
 
     pub async fn authorise(invoker: Arc<Mutex<Self>>, server: Arc<Mutex<Server>>) -> Result<String, String> {
+        if server.lock().await.revocations.is_revoked(&invoker.lock().await.key) {
+            return Err("Key is revoked".to_string());
+        }
         let challenge = Challenge::generate(128, &mut rand::rng());
         log::trace!("Sending authorisation challenge");
 
         Gateway::send_auth_challenge(invoker.clone(), &challenge).await?;
         log::trace!("Sended authorisation challenge");
 
-        let testing_system = server.lock().await.testing_system_side.testing_system.clone();
+        let testing_systems: Vec<_> = server.lock().await.testing_system_side.testing_systems.values().cloned().collect();
         // Getting certificate from testing system
-        let authorisation = server.lock().await.authorisation.clone();
+        let (authorisation, cert_cache_policy) = {
+            let server_locked = server.lock().await;
+            (server_locked.authorisation.clone(), server_locked.settings.cert_cache.clone())
+        };
 
-        let cert = authorisation.get_certificate_by_key(&invoker.lock().await.key, testing_system).await?;
+        let cert = authorisation.get_certificate_by_key(&invoker.lock().await.key, testing_systems, &cert_cache_policy).await?;
 
 
 
@@ -51,7 +91,7 @@ impl Invoker {
 
         let reader_unlocked = invoker.lock().await.reader.clone();
         let mut reader = reader_unlocked.lock().await;
-        let signed_challenge = Gateway::read_message_from(&mut reader).await?;
+        let signed_challenge = Gateway::read_message_from(&mut reader).await?.map_err(|err| err.to_string())?;
         log::trace!("Recieved signed_challenge message: {:?}", signed_challenge);
 
         if let InputMessage::SignedChallenge { bytes } = signed_challenge {
@@ -67,23 +107,164 @@ impl Invoker {
         }
     }
 
+    pub fn get_capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    pub fn get_submission(&self) -> Option<&Submission> {
+        self.submission.as_ref()
+    }
+
+    pub fn get_key(&self) -> &str {
+        &self.key
+    }
+
+    /// Checks that message echoes current assignment, so late messages of requeued, stopped or cancelled submissions are rejected.
+    /// `invoker.v1` invokers don't echo assignment, their messages are about current submission.
+    fn check_assignment(&self, assignment: Option<Assignment>) -> Result<&Submission, String> {
+        let assignment = match (assignment, self.protocol) {
+            (Some(assignment), _) => assignment,
+            (None, InvokerProtocol::V1) => return self.get_submission().ok_or_else(|| "Message is about submission, but invoker doesn't test anything".to_string()),
+            (None, _) => return Err("Message doesn't echo SUBMISSION and NONCE".to_string()),
+        };
+        let Some(submission) = self.get_submission() else {
+            return Err(format!("Message is about submission {}, but invoker doesn't test anything", assignment.submission_uuid));
+        };
+        if submission.uuid != assignment.submission_uuid || self.assignment != assignment.nonce {
+            return Err(format!("Message is about submission {} with nonce {}, but current one is {} with nonce {}", assignment.submission_uuid, assignment.nonce, submission.uuid, self.assignment));
+        }
+        Ok(submission)
+    }
+
+    /// Frees invoker from submission which message is about, checking and taking it under one lock so
+    /// the submission can't be requeued or reassigned in between.
+    fn take_assigned_submission(&mut self, assignment: Option<Assignment>) -> Result<Submission, String> {
+        self.check_assignment(assignment)?;
+        self.submission.take().ok_or_else(|| "Invoker doesn't test anything".to_string())
+    }
+
     pub fn get_submission_uuid(&self) -> Option<Uuid> {
-        self.submission_uuid
+        self.submission.as_ref().map(|submission| submission.uuid)
+    }
+
+    pub fn get_status(&self) -> InvokerStatus {
+        InvokerStatus {
+            protocol: self.protocol,
+            submission: self.get_submission_uuid(),
+            draining: self.draining,
+            rtt: self.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            deadline_misses: self.deadline_misses,
+            malformed_messages: self.protocol_errors.total(),
+        }
+    }
+
+    /// Sends CLOSE to invoker and stops its handlers, after that invoker is deleted.
+    pub async fn close(invoker: Arc<Mutex<Self>>) {
+        log::info!("Closing invoker | uuid = {}", invoker.lock().await.uuid);
+        if let Err(err) = Gateway::send_close(invoker.clone()).await {
+            log::error!("Couldn't send CLOSE message to invoker | error = {}", err);
+        }
+        Self::disconnect(invoker).await;
+    }
+
+    /// Stops handlers of invoker without notifying it, after that invoker is deleted.
+    pub async fn disconnect(invoker: Arc<Mutex<Self>>) {
+        invoker.lock().await.closed.send_replace(true);
+    }
+
+    /// Pings invoker every ping interval, if it doesn't answer for ping timeout it's declared dead and its submission is requeued.
+    pub async fn heartbeat(invoker: Arc<Mutex<Self>>, server: Arc<Mutex<Server>>) {
+        let (invoker_uuid, mut closed) = {
+            let invoker_locked = invoker.lock().await;
+            (invoker_locked.uuid, invoker_locked.closed.subscribe())
+        };
+        loop {
+            let (interval, timeout) = {
+                let server_locked = server.lock().await;
+                (server_locked.settings.invokers_ping_interval, server_locked.settings.invokers_ping_timeout)
+            };
+            let closing = tokio::select! {
+                _ = tokio::time::sleep(interval) => false,
+                _ = closed.wait_for(|closed| *closed) => true,
+            };
+            if closing {
+                return;
+            }
+
+            let silence = invoker.lock().await.last_pong_at.elapsed();
+            if silence > timeout {
+                log::error!("Invoker doesn't answer on pings, it's declared dead | uuid = {} | silence = {:?}", invoker_uuid, silence);
+                if let Err(err) = Self::delete(server.clone(), invoker.clone()).await {
+                    log::error!("Couldn't delete dead invoker | error = {} | uuid = {}", err, invoker_uuid);
+                }
+                Self::disconnect(invoker).await;
+                return;
+            }
+
+            {
+                let mut invoker_locked = invoker.lock().await;
+                if invoker_locked.ping_sent_at.is_none() {
+                    invoker_locked.ping_sent_at = Some(Instant::now());
+                }
+            }
+            // writer may be held by a stuck write, silence is checked again on the next tick anyway
+            match tokio::time::timeout(timeout, Gateway::send_ping(invoker.clone())).await {
+                Ok(Ok(())) => {},
+                Ok(Err(err)) => log::warn!("Couldn't send ping to invoker | error = {} | uuid = {}", err, invoker_uuid),
+                Err(_) => log::warn!("Couldn't send ping to invoker in time, its connection is stuck | timeout = {:?} | uuid = {}", timeout, invoker_uuid),
+            }
+        }
+    }
+
+    /// Stops giving new submissions to invoker, it's closed as soon as its current submission is finished.
+    pub async fn drain(invoker: Arc<Mutex<Self>>) {
+        let is_idle = {
+            let mut invoker_locked = invoker.lock().await;
+            log::info!("Draining invoker | uuid = {}", invoker_locked.uuid);
+            invoker_locked.draining = true;
+            invoker_locked.submission.is_none()
+        };
+        if is_idle {
+            Self::close(invoker).await;
+        }
     }
 
     pub async fn delete(server: Arc<Mutex<Server>>, invoker: Arc<Mutex<Self>>) -> Result<(), String> {
-        Self::finish_current_submission(server.clone(), invoker.clone()).await;
-        let uuid = invoker.lock().await.uuid;
-        server.lock().await.invokers_side.invokers.remove(&uuid);
+        let (uuid, identity) = {
+            let invoker_locked = invoker.lock().await;
+            (invoker_locked.uuid, invoker_locked.identity.clone())
+        };
+        {
+            let mut server_locked = server.lock().await;
+            if server_locked.invokers_side.invokers.get(&uuid).is_some_and(|registered| Arc::ptr_eq(registered, &invoker)) {
+                server_locked.invokers_side.invokers.remove(&uuid);
+                if let Some(identity) = &identity {
+                    server_locked.invokers_registry.seen(identity, uuid);
+                }
+                server_locked.events.publish(Event::InvokerDisconnected { invoker_uuid: uuid });
+            }
+        }
+        Self::requeue_current_submission(server, invoker).await;
         Ok(())
     }
 
+    /// Returns submission of invoker back to the queue, it's used when invoker can't finish it.
+    pub async fn requeue_current_submission(server: Arc<Mutex<Server>>, invoker: Arc<Mutex<Self>>) {
+        let Some(submission) = invoker.lock().await.submission.take() else {
+            return;
+        };
+        log::warn!("Invoker lost submission, requeueing it | invoker_uuid = {} | submission_uuid = {}", invoker.lock().await.uuid, submission.uuid);
+        Server::requeue_submission(server, submission).await;
+    }
+
     pub async fn run_submission(invoker_locked: &mut Invoker, submission: Submission) {
-        invoker_locked.submission_uuid = Some(submission.uuid);
+        invoker_locked.assignment += 1;
+        invoker_locked.submission = Some(submission.clone());
         let writer = invoker_locked.writer.clone();
+        let nonce = (invoker_locked.protocol >= InvokerProtocol::V2).then_some(invoker_locked.assignment);
         tokio::spawn(async move {
             let mut writer_locked = writer.lock().await;
-            if let Err(err) = Gateway::send_message_to(&mut writer_locked, OutputMessage::TestSubmission{submission}).await {
+            if let Err(err) = Gateway::send_message_to(&mut writer_locked, OutputMessage::TestSubmission{submission, nonce}).await {
                 log::error!("Couldn't send TestSubmission message to invoker | error = {}", err);
             };
         });
@@ -91,54 +272,155 @@ impl Invoker {
 
     pub async fn take_submission(invoker: Arc<Mutex<Invoker>>, server: Arc<Mutex<Server>>) -> Result<Option<Uuid>, String> {
         
-        {
+        let (capabilities, draining, mut closed) = {
             let invoker_locked = invoker.lock().await;
             log::info!("Invoker tries to take new submission | uuid = {}", invoker_locked.uuid);
-            if let Some(uuid) = invoker_locked.submission_uuid {
+            if let Some(uuid) = invoker_locked.get_submission_uuid() {
                 log::error!("Invoker already has submission and can't take new one | invoker_uuid = {} | submssion = {}", invoker_locked.uuid, uuid);
                 return Err("Invoker already has submission and can't take new one.".to_string());
             }
+            (invoker_locked.capabilities.clone(), invoker_locked.draining, invoker_locked.closed.subscribe())
+        };
+        if draining {
+            Self::close(invoker).await;
+            return Ok(None);
         }
-        let submission = {
-            let submissions_pool_receiver_cloned = server.lock().await.invokers_side.submissions_pool_receiver.clone();
-            let mut submissions_pool_receiver = submissions_pool_receiver_cloned.lock().await; // firstly we'll lock submissions, as an indicator of submissions-routing
-            submissions_pool_receiver.recv().await
+        let submissions_pool = server.lock().await.submissions_pool.clone();
+        let submission = tokio::select! {
+            taken = submissions_pool.take(&capabilities) => Some(taken),
+            _ = closed.wait_for(|closed| *closed) => None,
+        };
+        let Some((submission, waited)) = submission else {
+            log::info!("Invoker stopped waiting for new submission, it was closed | uuid = {}", invoker.lock().await.uuid);
+            return Ok(None);
         };
-        let mut invoker_locked = invoker.lock().await;
 
-        if let Some(submission) = submission {
-            let submission_uuid = submission.uuid;
-            log::info!("Invoker takes new submission | submission_uuid = {}", submission_uuid);
-            Self::run_submission(&mut invoker_locked, submission).await;
-            log::info!("Invoker taked new submission | submission_uuid = {}", submission_uuid);
-            drop(invoker_locked);
-            Ok(Some(submission_uuid))
-        } else {
-            log::info!("Invoker can't take new submission | uuid = {}", invoker_locked.uuid);
+        // invoker could be deleted or drained while it was waiting for submission, so submission should be returned back
+        let server_locked = server.lock().await;
+        let mut invoker_locked = invoker.lock().await;
+        if invoker_locked.draining || !server_locked.invokers_side.invokers.get(&invoker_locked.uuid).is_some_and(|registered| Arc::ptr_eq(registered, &invoker)) {
+            log::info!("Invoker can't take new submission, it was deleted or drained | uuid = {}", invoker_locked.uuid);
             drop(invoker_locked);
-            Ok(None)
+            drop(server_locked);
+            submissions_pool.push_front(submission).await;
+            return Ok(None);
         }
+
+        server_locked.metrics.queue_waited(waited);
+        server_locked.events.publish(Event::SubmissionAssigned { submission_uuid: submission.uuid, invoker_uuid: invoker_locked.uuid });
+        let (submission_uuid, submission_key) = (submission.uuid, submission.key());
+        let deadline = submission.deadline(&server_locked.settings.deadline_policy);
+        log::info!("Invoker takes new submission | submission_uuid = {} | deadline = {:?}", submission_uuid, deadline);
+        Self::run_submission(&mut invoker_locked, submission).await;
+        server_locked.journal.assign(submission_key, invoker_locked.uuid);
+        tokio::spawn(Self::watchdog(invoker.clone(), server.clone(), invoker_locked.assignment, deadline));
+        log::info!("Invoker taked new submission | submission_uuid = {}", submission_uuid);
+        drop(invoker_locked);
+        drop(server_locked);
+        Ok(Some(submission_uuid))
     }
 
-    pub async fn finish_current_submission(server: Arc<Mutex<Server>>, invoker: Arc<Mutex<Invoker>>) {
-        if let Some(submission_uuid) = invoker.lock().await.submission_uuid.clone() { 
-            let _ = Server::remove_tests_result(server.clone(), submission_uuid);
-        } else {
-            log::error!("Something went wrong, and `submission_uuid` of `Invoker` is set to None, but submission was finished.");
+    /// Stops testing of submission if it isn't finished until deadline, submission is requeued or failed with TE.
+    /// Future is boxed, because watchdog and `take_submission` spawn each other.
+    fn watchdog(invoker: Arc<Mutex<Invoker>>, server: Arc<Mutex<Server>>, assignment: u64, deadline: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async move {
+            let mut closed = invoker.lock().await.closed.subscribe();
+            let expired = tokio::select! {
+                _ = tokio::time::sleep(deadline) => true,
+                _ = closed.wait_for(|closed| *closed) => false,
+            };
+            if !expired {
+                return;
+            }
+
+            let submission = {
+                let mut invoker_locked = invoker.lock().await;
+                if invoker_locked.assignment != assignment || invoker_locked.submission.is_none() {
+                    return; // submission was finished in time
+                }
+                invoker_locked.deadline_misses += 1;
+                log::error!("Invoker missed deadline of submission | uuid = {} | submission_uuid = {:?} | deadline = {:?} | deadline_misses = {}", invoker_locked.uuid, invoker_locked.get_submission_uuid(), deadline, invoker_locked.deadline_misses);
+                invoker_locked.submission.take()
+            };
+            if let Err(err) = Gateway::send_stop(invoker.clone()).await {
+                log::error!("Couldn't send STOP message to invoker | error = {}", err);
+            }
+            if let Some(submission) = submission {
+                Server::requeue_submission(server.clone(), submission).await;
+            }
+            tokio::spawn(Self::take_submission(invoker, server));
+        })
+    }
+
+    /// Frees invoker from its current submission if it's `matches`, checking and taking it under one lock,
+    /// then sends STOP and lets invoker take the next one. `None` means that invoker isn't testing such submission.
+    pub async fn stop_submission_if(server: Arc<Mutex<Server>>, invoker: Arc<Mutex<Invoker>>, matches: impl FnOnce(&Submission) -> bool) -> Option<(Submission, Vec<TestResult>)> {
+        let submission = {
+            let mut invoker_locked = invoker.lock().await;
+            if !invoker_locked.submission.as_ref().is_some_and(matches) {
+                return None;
+            }
+            invoker_locked.submission.take()?
+        };
+        if let Err(err) = Gateway::send_stop(invoker.clone()).await {
+            log::error!("Couldn't send STOP message to invoker | error = {}", err);
+        }
+        let result = Self::finish_submission(server.clone(), submission).await;
+        tokio::spawn(Self::take_submission(invoker, server));
+        Some(result)
+    }
+
+    /// Marks submission which invoker was freed from as completed and collects its tests results.
+    async fn finish_submission(server: Arc<Mutex<Server>>, submission: Submission) -> (Submission, Vec<TestResult>) {
+        server.lock().await.journal.complete(submission.key());
+        let tests_results = Server::remove_tests_result(server, &submission.key()).await.unwrap_or_else(|| {
+            log::error!("invoker_handler: Undefined test results. | submission_uuid: {:?}", submission.uuid);
+
+            Vec::new()
+        });
+        (submission, tests_results)
+    }
+
+    /// Replies with PROTOCOL_ERROR and spends error budget of invoker, returns `false` if budget is exceeded.
+    async fn protocol_error(invoker: Arc<Mutex<Self>>, server: Arc<Mutex<Server>>, error: &ProtocolError) -> bool {
+        let (budget, window) = {
+            let server_locked = server.lock().await;
+            server_locked.metrics.protocol_error("invoker");
+            (server_locked.settings.protocol_error_budget, server_locked.settings.protocol_error_window)
+        };
+        if let Err(err) = Gateway::send_protocol_error(invoker.clone(), error).await {
+            log::error!("Couldn't send PROTOCOL_ERROR message to invoker | error = {}", err);
         }
-        invoker.lock().await.submission_uuid = None;
+        invoker.lock().await.protocol_errors.spend(budget, window)
     }
 
     pub async fn message_handler(invoker: Arc<Mutex<Self>>, server: Arc<Mutex<Server>>) -> Result<String, String> {
         let reader = invoker.lock().await.reader.clone();
         let invoker_uuid = invoker.lock().await.uuid.clone();
+        let mut closed = invoker.lock().await.closed.subscribe();
         'lp: loop {
             let mut reader_locked = reader.lock().await;
-            let message = match Gateway::read_message_from(&mut reader_locked).await {
+            let message = tokio::select! {
+                message = Gateway::read_message_from(&mut reader_locked) => match message {
+                    Ok(message) => message,
+                    Err(err) => {
+                        log::info!("invoker_side: Recieved a message | error = {:?} | invoker_uuid = {:?}", err, invoker_uuid);
+                    return Err("Reading error".to_string());
+                    }
+                },
+                _ = closed.wait_for(|closed| *closed) => {
+                    return Ok("Invoker closed".to_string());
+                },
+            };
+            let message = match message {
                 Ok(message) => message,
                 Err(err) => {
-                    log::info!("invoker_side: Recieved a message | error = {:?} | invoker_uuid = {:?}", err, invoker_uuid);
-                return Err("Reading error".to_string());
+                    log::error!("invoker_handler: Invoker sent malformed message | error = {} | invoker_uuid = {:?}", err, invoker_uuid);
+                    if !Self::protocol_error(invoker.clone(), server.clone(), &err).await {
+                        Self::close(invoker.clone()).await;
+                        return Err(format!("Invoker exceeded error budget, last error: {err}"));
+                    }
+                    continue 'lp;
                 }
             };
             log::info!("invoker_handler: Recieeved message from invoker | invoker_uuid = {:?}", invoker_uuid);
@@ -149,29 +431,22 @@ impl Invoker {
 
                     return Ok(exit_code);
                 },
-                InputMessage::Verdict { verdict, message } => {
+                InputMessage::Verdict { assignment, verdict, message } => {
                     log::info!("Working on VERDICT message from invoker | verdict = {:?}", verdict);
-                    let Some(submission_uuid) = invoker.lock().await.submission_uuid.clone() else {
-                        log::error!("invoker_side: Invoker send VERDICT message, before taking submission");
-                        continue 'lp;
+                    let submission = match invoker.lock().await.take_assigned_submission(assignment) {
+                        Ok(submission) => submission,
+                        Err(err) => {
+                            log::error!("invoker_handler: Stale VERDICT message is rejected | error = {} | invoker_uuid = {:?}", err, invoker_uuid);
+                            continue 'lp;
+                        }
                     };
+                    let (submission, test_results) = Self::finish_submission(server.clone(), submission).await;
                     
                     let invoker = invoker.clone();
                     let server = server.clone();
                     tokio::spawn(async move {
-                        let test_results = server.lock().await.tests_results.remove(&submission_uuid).unwrap_or_else(|| {
-                            log::error!("invoker_handler: Undefined test results. | submission_uuid: {:?}", submission_uuid);
-
-                            Vec::new()
-                        });
-                        let Some(testing_system) = server.lock().await.testing_system_side.testing_system.clone() else {
-                            log::error!("invoker_handler: Recieved verdict message, but testing_systeem didn't connect. | invoker_uuid = {:?}", invoker_uuid);
-
-                            return;
-                        };
-                        tokio::spawn(testing_system::gateway::Gateway::send_submission_verdict(testing_system, verdict, submission_uuid, test_results, message));
+                        tokio::spawn(TestingSystemSide::send_submission_verdict(server.clone(), submission.origin, verdict, submission.uuid, test_results, message));
 
-                        Self::finish_current_submission(server.clone(), invoker.clone()).await;
                         match Self::take_submission(invoker.clone(), server.clone()).await {
                             Ok(Some(uuid)) => log::info!("Invoker taked new submission after completing previous | uuid = {:?} | submission_uuid = {:?}", invoker_uuid, uuid),
                             Ok(None) => log::info!("Invoker didn't take new submission after completing previous | uuid = {:?}", invoker_uuid),
@@ -179,36 +454,21 @@ impl Invoker {
                         }
                     });
                 }
-                InputMessage::TestVerdict { result, test, data } => {
-                    {
-                        log::info!("Working on TEST_VERDICT m.essage from invoker | result = {:?} | test = {:?}", result, test);
-                        let invoker = invoker.clone();
-                        let server = server.clone();
-                        let result = result.clone();
-                        tokio::spawn(async move {
-                            let Some(submission_uuid) = invoker.lock().await.submission_uuid.clone() else {
-                                log::error!("invoker_handler: Invoker sent test verdict, but hasn't current submission. | invoker_uuid: {:?}", invoker_uuid);
-
-                                return;
-                            };
-                            let Some(testing_system) = server.lock().await.testing_system_side.testing_system.clone() else {
-                                log::error!("invoker_handler: Recieved test verdict message, but testing_systeem didn't connect. | invoker_uuid = {:?}", invoker_uuid);
-
-                                return;
-                            };
-                            tokio::spawn(testing_system::gateway::Gateway::send_test_verdict(testing_system, result, test, data, submission_uuid));
-                        });
-                    }
+                InputMessage::TestVerdict { assignment, result, test, data } => {
+                    log::info!("Working on TEST_VERDICT m.essage from invoker | result = {:?} | test = {:?}", result, test);
+                    let (submission_uuid, origin) = match invoker.lock().await.check_assignment(assignment) {
+                        Ok(submission) => (submission.uuid, submission.origin.clone()),
+                        Err(err) => {
+                            log::error!("invoker_handler: Stale TEST message is rejected | error = {} | invoker_uuid = {:?}", err, invoker_uuid);
+                            continue 'lp;
+                        }
+                    };
+                    server.lock().await.metrics.test_verdict(&String::from(result.verdict.clone()));
+                    tokio::spawn(TestingSystemSide::send_test_verdict(server.clone(), origin.clone(), result.clone(), test, data, submission_uuid));
                     'bl : {
-                        let invoker = invoker.clone();
-                        let server = server.clone();
-                        let Some(submission_uuid) = invoker.lock().await.submission_uuid.clone() else {
-                            log::error!("invoker_handler: Invoker sent test verdict, but hasn't current submission. | invoker_uuid: {:?}", invoker_uuid);
-
-                            break 'bl;
-                        };
                         let mut server_locked = server.lock().await;
-                        let Some(tests_results) = server_locked.tests_results.get_mut(&submission_uuid) else {
+                        server_locked.events.publish(Event::TestVerdict { submission_uuid, test, result: result.clone() });
+                        let Some(tests_results) = server_locked.tests_results.get_mut(&(origin, submission_uuid)) else {
                             log::error!("invoker_handler: Invoke sent test verdict, tests result isn't predefinted | invoker_uuid: {:?}", invoker_uuid);
 
                             break 'bl;
@@ -221,32 +481,40 @@ impl Invoker {
                         *test_result = result;
                     }
                 },
+                InputMessage::Pong => {
+                    let mut invoker_locked = invoker.lock().await;
+                    invoker_locked.last_pong_at = Instant::now();
+                    if let Some(ping_sent_at) = invoker_locked.ping_sent_at.take() {
+                        invoker_locked.rtt = Some(ping_sent_at.elapsed());
+                    }
+                    log::trace!("Recieved pong from invoker | uuid = {} | rtt = {:?}", invoker_uuid, invoker_locked.rtt);
+                },
                 InputMessage::Error { message } => {
                     log::warn!("Invoker returned error | message = {} | uuid = {}", message, invoker_uuid);
                 },
-                InputMessage::OpError { message } => {
+                InputMessage::OpError { assignment, message } => {
                     log::warn!("Invoker returned operror | message = {} | uuid = {}", message, invoker_uuid);
-                    
-                    if let Some(testing_system) = server.lock().await.testing_system_side.testing_system.clone() {
-                        let Some(submission_uuid) = invoker.lock().await.submission_uuid.clone() else {
-                            log::error!("invoker_side: Invoker send OPERROR message, before taking submission");
+                    let submission = match invoker.lock().await.take_assigned_submission(assignment) {
+                        Ok(submission) => submission,
+                        Err(err) => {
+                            log::error!("invoker_handler: Stale OPERROR message is rejected | error = {} | invoker_uuid = {:?}", err, invoker_uuid);
                             continue 'lp;
-                        };
-                        let test_results = server.lock().await.tests_results.remove(&submission_uuid).unwrap_or_else(|| {
-                            log::error!("invoker_handler: Undefined test results. | submission_uuid: {:?}", submission_uuid);
-
-                            Vec::new()
-                        });
+                        }
+                    };
+                    let (submission, test_results) = Self::finish_submission(server.clone(), submission).await;
 
-                        tokio::spawn(testing_system::gateway::Gateway::send_submission_verdict(testing_system, crate::server::verdict::Verdict::TE, submission_uuid, test_results, Err(message)));
+                    tokio::spawn(TestingSystemSide::send_submission_verdict(server.clone(), submission.origin, crate::server::verdict::Verdict::TE, submission.uuid, test_results, Err(message)));
 
-                        Self::finish_current_submission(server.clone(), invoker.clone()).await;
-                        match Self::take_submission(invoker.clone(), server.clone()).await {
+                    // waiting for new submission mustn't stop reading messages of invoker
+                    let invoker = invoker.clone();
+                    let server = server.clone();
+                    tokio::spawn(async move {
+                        match Self::take_submission(invoker, server).await {
                             Ok(Some(uuid)) => log::info!("Invoker taked new submission after operror'ing on previous | uuid = {:?} | submission_uuid = {:?}", invoker_uuid, uuid),
                             Ok(None) => log::info!("Invoker didn't take new submission after operror'ing previous | uuid = {:?}", invoker_uuid),
                             Err(error) => log::error!("Invoker couldn't take new submission due to the error | error = {} | uuid = {:?}", error.to_string(), invoker_uuid)
                         }
-                    }
+                    });
                 },
                 _ => {}
             }
@@ -0,0 +1,131 @@
+use std::{collections::BTreeMap, fs, path::PathBuf, str::FromStr, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::json_file::{JsonFile, Snapshot};
+
+/// What happens when invoker connects while another session of the same identity is alive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicateSession {
+    Reject, // new connection is closed
+    TakeOver, // old connection is closed and its submission is requeued
+}
+
+impl FromStr for DuplicateSession {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "take_over" => Ok(Self::TakeOver),
+            _ => Err(format!("Unknown duplicate session policy {s}, expected `reject` or `take_over`")),
+        }
+    }
+}
+
+/// Registry entry of invoker: key it authorised with, or key and declared ID if key is shared by every invoker.
+/// Invoker with shared key and without ID has no stable identity.
+pub fn identity_of(key: &str, declared: Option<Uuid>, is_key_shared: bool) -> Option<String> {
+    match (is_key_shared, declared) {
+        (false, _) => Some(key.to_string()),
+        (true, Some(declared)) => Some(format!("{key}#{declared}")),
+        (true, None) => None,
+    }
+}
+
+/// Stable identity of invoker, it's bound to key invoker authorised with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identity {
+    pub uuid: Uuid,
+    pub name: String,
+    pub first_seen: u64, // unix time in seconds
+    pub last_seen: u64,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs())
+}
+
+/// Every invoker which has ever authorised, kept in JSON file `{<identity>: <identity>}`, see `identity_of`.
+pub struct InvokersRegistry {
+    file: JsonFile,
+    identities: BTreeMap<String, Identity>,
+}
+
+impl InvokersRegistry {
+    /// Missing file means that no invoker is known.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let path = path.into();
+        let identities = if path.exists() {
+            let content = fs::read_to_string(&path)
+                .map_err(|err| format!("Can't read invokers registry {}: {err}", path.display()))?;
+            serde_json::from_str(&content)
+                .map_err(|err| format!("Can't parse invokers registry {}: {err}", path.display()))?
+        } else {
+            BTreeMap::new()
+        };
+        log::info!("invokers_registry: Loaded | path = {} | invokers = {}", path.display(), identities.len());
+        Ok(Self { file: JsonFile::new("invokers registry", path), identities })
+    }
+
+    /// Known identity keeps its uuid whatever invoker declares, new one gets declared uuid unless another identity has it.
+    pub fn resolve(&self, identity: Option<&str>, declared: Option<Uuid>) -> Uuid {
+        if let Some(identity) = identity.and_then(|identity| self.identities.get(identity)) {
+            if declared.is_some_and(|declared| declared != identity.uuid) {
+                log::warn!("invokers_registry: Invoker declared another ID, the registered one is used | declared = {:?} | uuid = {}", declared, identity.uuid);
+            }
+            return identity.uuid;
+        }
+        match declared {
+            Some(uuid) if !self.identities.values().any(|identity| identity.uuid == uuid) => uuid,
+            _ => Uuid::from_bytes(rand::random::<[u8; 16]>()),
+        }
+    }
+
+    /// Registers identity on first authorisation and updates its last seen time, registry is saved in background.
+    pub fn seen(&mut self, identity: &str, uuid: Uuid) {
+        let now = now();
+        let entry = self.identities.entry(identity.to_string()).or_insert_with(|| {
+            log::info!("invokers_registry: New invoker registered | uuid = {}", uuid);
+            Identity {
+                uuid,
+                name: uuid.to_string(),
+                first_seen: now,
+                last_seen: now,
+                labels: BTreeMap::new(),
+            }
+        });
+        entry.last_seen = now;
+        match self.file.snapshot(&self.identities) {
+            Ok(snapshot) => {
+                tokio::spawn(async move {
+                    if let Err(err) = snapshot.save().await {
+                        log::error!("invokers_registry: Couldn't save registry | error = {}", err);
+                    }
+                });
+            },
+            Err(err) => log::error!("invokers_registry: Couldn't save registry | error = {}", err),
+        }
+    }
+
+    pub fn list(&self) -> &BTreeMap<String, Identity> {
+        &self.identities
+    }
+
+    /// Changes display name and labels of invoker, `None` keeps current value.
+    /// Returned snapshot should be saved after the server lock is released.
+    pub fn update(&mut self, uuid: Uuid, name: Option<String>, labels: Option<BTreeMap<String, String>>) -> Result<Snapshot, String> {
+        let Some(identity) = self.identities.values_mut().find(|identity| identity.uuid == uuid) else {
+            return Err(format!("Invoker {uuid} isn't registered"));
+        };
+        if let Some(name) = name {
+            identity.name = name;
+        }
+        if let Some(labels) = labels {
+            identity.labels = labels;
+        }
+        self.file.snapshot(&self.identities)
+    }
+}
@@ -1,73 +1,110 @@
-use tokio::{net::{TcpListener, TcpStream}, sync::{mpsc, Mutex}, task::JoinHandle};
-use std::{collections::HashMap, sync::Arc};
-use ratchet_rs::{SubprotocolRegistry, WebSocketConfig, WebSocket};
+use tokio::{net::TcpListener, sync::Mutex, task::JoinHandle};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use ratchet_rs::{WebSocketConfig, WebSocket};
 use ratchet_deflate::{Compression, Deflate, DeflateConfig, DeflateExtProvider};
-use crate::{server::invoker::Invoker, COMPRESSION_LEVEL, MAX_MESSAGE_SIZE};
+use crate::server::invoker::{Invoker, InvokerStatus};
 use super::invoker::gateway::Gateway as InvokerGateway;
 use super::invoker::gateway::InputMessage as InvokerInMessage;
+use super::invoker::gateway::OutputMessage as InvokerOutMessage;
 use uuid::Uuid;
-use super::submission::Submission;
 
-use super::Server;
+use super::{events::Event, invokers_registry::{identity_of, DuplicateSession}, protocol::InvokerProtocol, tls::{MaybeTlsStream, TlsAcceptor, TlsConfig}, Server};
+
+const UNROUTABLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct InvokersSide {
-    pub submissions_pool_receiver: Arc<Mutex<mpsc::Receiver<Submission>>>,
     pub invokers: HashMap<Uuid, Arc<Mutex<Invoker>>>,
 }
 
 impl InvokersSide {
-    pub fn new(spr: mpsc::Receiver<Submission>) -> Self {
+    pub fn new() -> Self {
         Self {
-            submissions_pool_receiver: Arc::new(Mutex::new(spr)),
             invokers: HashMap::new(),
         }
     }
 
-    pub async fn start(server: Arc<Mutex<Server>>, address: String) -> Result<(), String> {
+    /// With `tls` invokers connect over wss://, certificate is reloaded as soon as its files change.
+    pub async fn start(server: Arc<Mutex<Server>>, address: String, tls: Option<TlsConfig>) -> Result<(), String> {
+        let tls_acceptor = match tls.map(TlsAcceptor::new).transpose() {
+            Ok(tls_acceptor) => tls_acceptor,
+            Err(err) => {
+                log::error!("invoker_side: Can't load TLS certificate | error = {}", err);
+
+                return Err(format!("Can't load TLS certificate: {err}"));
+            }
+        };
+        if let Some(tls_acceptor) = &tls_acceptor {
+            tokio::spawn(TlsAcceptor::watcher(tls_acceptor.clone()));
+        }
         let Ok(listener) = TcpListener::bind(address).await else {
             log::error!("invoker_side: Can't bind tcp listener for invokers side");
 
             return Err("Can't bind tcp listener for invokers side".to_string());
         };
-        log::trace!("invoker_side: Binded");
+        log::trace!("invoker_side: Binded | tls = {}", tls_acceptor.is_some());
 
         loop {
             if let Ok((connection, address)) = listener.accept().await {
                 log::trace!("invoker_side: Finded connection | address = {}", address);
-                match ratchet_rs::accept_with(connection,
-                    WebSocketConfig {
-                        max_message_size: MAX_MESSAGE_SIZE,
-                    },
-                    DeflateExtProvider::with_config(DeflateConfig {
-                        compression_level: Compression::new(COMPRESSION_LEVEL),
-                        ..Default::default()
-                    }),
-                    SubprotocolRegistry::default()
-                ).await {
-                    Ok(stream) => {
-                        log::trace!("invoker_side: Found new invoker");
-                        let server = server.clone();
-                        tokio::spawn(async move {
-                            let Ok(upgr) = stream.upgrade().await else {
-                                log::error!("invoker_side: Couldn't update to ws");
-                                
+                let tls_acceptor = tls_acceptor.as_deref().map(TlsAcceptor::acceptor);
+                let (max_message_size, compression_level) = {
+                    let server_locked = server.lock().await;
+                    (server_locked.settings.max_message_size, server_locked.settings.compression_level)
+                };
+                let server = server.clone();
+                tokio::spawn(async move {
+                    let connection = match tls_acceptor {
+                        Some(tls_acceptor) => match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls_acceptor.accept(connection)).await {
+                            Ok(Ok(connection)) => MaybeTlsStream::Tls(Box::new(connection.into())),
+                            Ok(Err(err)) => {
+                                log::error!("invoker_side: TLS handshake failed | error = {} | address = {}", err, address);
+
                                 return;
-                            };
-                            
-                            if let Err(err) = Self::add_invoker(server, upgr.into_websocket()).await {
-                                log::error!("invoker_side: Adding invoker falied | error = {}", err);
-                            };
-                        });
-                    },
-                    Err(err) => {
-                        log::error!("invoker_side: Failed connection | error = {}", err);
-                    }
-                }
+                            },
+                            Err(_) => {
+                                log::error!("invoker_side: TLS handshake timed out | timeout = {:?} | address = {}", TLS_HANDSHAKE_TIMEOUT, address);
+
+                                return;
+                            }
+                        },
+                        None => MaybeTlsStream::Plain(connection),
+                    };
+                    let stream = match ratchet_rs::accept_with(connection,
+                        WebSocketConfig {
+                            max_message_size,
+                        },
+                        DeflateExtProvider::with_config(DeflateConfig {
+                            compression_level: Compression::new(compression_level),
+                            ..Default::default()
+                        }),
+                        InvokerProtocol::registry()
+                    ).await {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            log::error!("invoker_side: Failed connection | error = {}", err);
+
+                            return;
+                        }
+                    };
+                    log::trace!("invoker_side: Found new invoker");
+                    let Ok(upgr) = stream.upgrade().await else {
+                        log::error!("invoker_side: Couldn't update to ws");
+
+                        return;
+                    };
+                    let protocol = InvokerProtocol::negotiated(upgr.subprotocol.as_ref());
+                    log::trace!("invoker_side: Protocol negotiated | protocol = {} | address = {}", protocol.name(), address);
+
+                    if let Err(err) = Self::add_invoker(server, upgr.into_websocket(), protocol).await {
+                        log::error!("invoker_side: Adding invoker falied | error = {}", err);
+                    };
+                });
             }
         }
     }
-    pub async fn add_invoker(server: Arc<Mutex<Server>>, stream: WebSocket<TcpStream, Deflate>) -> Result<JoinHandle<Result<String, String>>, String> {
-        let Ok((writer, mut reader)) = stream.split() else {
+    pub async fn add_invoker(server: Arc<Mutex<Server>>, stream: WebSocket<MaybeTlsStream, Deflate>, protocol: InvokerProtocol) -> Result<JoinHandle<Result<String, String>>, String> {
+        let Ok((mut writer, mut reader)) = stream.split() else {
             log::error!("invoker_side: Stream couldn't be splited");
 
             return Err("Stream couldn't be splited".to_string());
@@ -78,26 +115,76 @@ impl InvokersSide {
 
             return Err("Couldn't read message from stream".to_string());
         };
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => {
+                log::error!("invoker_side: Invoker sent malformed message instead of TOKEN | error = {}", err);
+                server.lock().await.metrics.protocol_error("invoker");
+                let _ = InvokerGateway::send_message_to(&mut writer, InvokerOutMessage::ProtocolError { message: err.to_string() }).await;
+
+                return Err(format!("Malformed TOKEN message: {err}"));
+            }
+        };
         log::trace!("invoker_side: Sent connect message");
 
-        if let InvokerInMessage::Token { uuid, key } = message {
-            let invoker = Arc::new(Mutex::new(Invoker::new(uuid, key, reader, writer)));
+        if let InvokerInMessage::Token { uuid, key, capabilities } = message {
+            let (uuid, identity) = {
+                let server_locked = server.lock().await;
+                let identity = identity_of(&key, uuid, server_locked.authorisation.is_key_shared());
+                (server_locked.invokers_registry.resolve(identity.as_deref(), uuid), identity)
+            };
+            log::trace!("invoker_side: Invoker declared capabilities | uuid = {} | capabilities = {:?}", uuid, capabilities);
+            let invoker = Arc::new(Mutex::new(Invoker::new(uuid, key.clone(), identity.clone(), protocol, capabilities, reader, writer)));
+            server.lock().await.events.publish(Event::InvokerConnected { invoker_uuid: uuid });
 
             // Need invoker authorisation
             
             if let Err(err) = Invoker::authorise(invoker.clone(), server.clone()).await {
+                {
+                    let server_locked = server.lock().await;
+                    server_locked.metrics.authorisation_failed(server_locked.authorisation.name());
+                    server_locked.events.publish(Event::InvokerDisconnected { invoker_uuid: uuid });
+                }
                 let _ = InvokerGateway::send_auth_verdict(invoker, false).await;
                 log::error!("Droped connection to invoker while authorisation: {err:?}");
                 return Err(err);
             };
 
-            {
+            let taken_over = {
                 let mut server_locked = server.lock().await;
-                server_locked.invokers_side.invokers.insert(uuid, invoker.clone());
+                let rejection = if server_locked.revocations.is_revoked(&key) {
+                    Some("Key was revoked while authorising".to_string()) // revoked while invoker was authorising
+                } else if server_locked.invokers_side.invokers.contains_key(&uuid) && server_locked.settings.duplicate_session == DuplicateSession::Reject {
+                    Some(format!("Invoker {uuid} is already connected"))
+                } else {
+                    None
+                };
+                if let Some(rejection) = rejection {
+                    server_locked.events.publish(Event::InvokerDisconnected { invoker_uuid: uuid });
+                    drop(server_locked);
+                    log::error!("invoker_side: Invoker rejected | uuid = {} | reason = {}", uuid, rejection);
+                    Invoker::close(invoker).await;
+                    return Err(rejection);
+                }
+                let taken_over = server_locked.invokers_side.invokers.insert(uuid, invoker.clone());
+                if taken_over.is_some() {
+                    server_locked.events.publish(Event::InvokerDisconnected { invoker_uuid: uuid });
+                }
+                if let Some(identity) = &identity {
+                    server_locked.invokers_registry.seen(identity, uuid);
+                }
+                server_locked.events.publish(Event::InvokerAuthorised { invoker_uuid: uuid });
+                taken_over
+            };
+            if let Some(taken_over) = taken_over {
+                log::warn!("invoker_side: New session took over invoker, the old one is closed | uuid = {}", uuid);
+                Invoker::requeue_current_submission(server.clone(), taken_over.clone()).await;
+                Invoker::close(taken_over).await;
             }
             log::trace!("invoker_side: Added | uuid = {}", uuid);
 
             tokio::spawn(Invoker::take_submission(invoker.clone(), server.clone()));
+            tokio::spawn(Invoker::heartbeat(invoker.clone(), server.clone()));
 
             Ok(tokio::spawn(async move {
                 let result = Invoker::message_handler(invoker.clone(), server.clone()).await;
@@ -112,7 +199,8 @@ impl InvokersSide {
             Err("Invoker conntected, but don't send TOKEN message first.".to_string())
         }
     }
-    pub async fn delete_invoker(server: Arc<Mutex<Server>>, uuid: Uuid) -> Result<(), String> {
+    /// Drains invoker, or with `force` closes it immediately and requeues its submission.
+    pub async fn delete_invoker(server: Arc<Mutex<Server>>, uuid: Uuid, force: bool) -> Result<(), String> {
         let invoker = {
             let server_locked = server.lock().await;
             let Some(invoker) = server_locked.invokers_side.invokers.get(&uuid) else {
@@ -121,15 +209,72 @@ impl InvokersSide {
             invoker.clone()
         };
 
-        tokio::spawn(Invoker::take_submission(invoker.clone(), server.clone()));
-        Invoker::delete(server.clone(), invoker.clone()).await?;
+        if force {
+            Invoker::delete(server.clone(), invoker.clone()).await?;
+            Invoker::close(invoker).await;
+        } else {
+            Invoker::drain(invoker).await;
+        }
         return Ok(());
     }
-    pub async fn get_invokers_status(&self) -> HashMap<Uuid, Option<Uuid>> {
+    /// Revokes key and closes every invoker using it, their submissions are returned to the queue.
+    /// Invokers are closed even if revocation can't be saved, then key is revoked only until restart.
+    pub async fn revoke_key(server: Arc<Mutex<Server>>, key: &str, reason: Option<String>) -> Result<usize, String> {
+        let (snapshot, invokers) = {
+            let mut server_locked = server.lock().await;
+            let snapshot = server_locked.revocations.revoke(key, reason)?;
+            let mut invokers = Vec::new();
+            for invoker in server_locked.invokers_side.invokers.values() {
+                if invoker.lock().await.get_key() == key {
+                    invokers.push(invoker.clone());
+                }
+            }
+            (snapshot, invokers)
+        };
+        let saved = snapshot.save().await;
+
+        let disconnected = invokers.len();
+        for invoker in invokers {
+            Invoker::delete(server.clone(), invoker.clone()).await?;
+            Invoker::close(invoker).await;
+        }
+        log::info!("invoker_side: Key revoked | key = {} | disconnected = {}", key, disconnected);
+        if let Err(err) = saved {
+            log::error!("invoker_side: Couldn't save revocation, key is revoked until restart | error = {} | key = {}", err, key);
+            return Err(format!("Key is revoked until restart, {disconnected} invokers are disconnected, but revocation couldn't be saved: {err}"));
+        }
+        Ok(disconnected)
+    }
+    /// Fails queued submissions which no connected invoker is able to run.
+    pub async fn unroutable_sweeper(server: Arc<Mutex<Server>>) {
+        let mut interval = tokio::time::interval(UNROUTABLE_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let invokers: Vec<Arc<Mutex<Invoker>>> = server.lock().await.invokers_side.invokers.values().cloned().collect();
+            if invokers.is_empty() {
+                continue; // nobody is connected, so submissions just wait for invokers
+            }
+            let mut capabilities = Vec::new();
+            for invoker in invokers {
+                capabilities.push(invoker.lock().await.get_capabilities().clone());
+            }
+            let (submissions_pool, unroutable_timeout) = {
+                let server_locked = server.lock().await;
+                (server_locked.submissions_pool.clone(), server_locked.settings.unroutable_timeout)
+            };
+            for submission in submissions_pool.remove_unroutable(&capabilities, unroutable_timeout).await {
+                log::error!("invoker_side: No connected invoker can run submission | submission_uuid = {} | requirements = {}", submission.uuid, submission.requirements);
+                let message = format!("No connected invoker can run submission with requirements: {}", submission.requirements);
+                Server::fail_submission(server.clone(), submission.uuid, submission.origin, message).await;
+            }
+        }
+    }
+
+    pub async fn get_invokers_status(&self) -> HashMap<Uuid, InvokerStatus> {
         let invokers_side = self.invokers.clone();
         let mut map = HashMap::new();
         for (uuid, invoker) in invokers_side {
-            map.insert(uuid, invoker.lock().await.get_submission_uuid());
+            map.insert(uuid, invoker.lock().await.get_status());
         }
         map
     }
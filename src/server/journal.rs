@@ -0,0 +1,368 @@
+use std::{collections::HashMap, fs::{self, File, OpenOptions}, io::{Read, Write}, path::PathBuf, sync::Arc, time::Duration};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use uuid::Uuid;
+use super::{submission::{Submission, SubmissionKey}, Server};
+
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
+const COMPACTION_THRESHOLD: usize = 1000; // completed submissions since last compaction
+
+/// Event of submission's lifecycle, written to journal.
+/// Record on disk: <header length [4 bytes]><header [json]><data length [8 bytes]><data>, data is used only by `Enqueue`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event")]
+enum Record {
+    Enqueue { submission: Submission },
+    Assign { origin: String, uuid: Uuid, invoker: Uuid },
+    Requeue { origin: String, uuid: Uuid, attempts: u8 },
+    Complete { origin: String, uuid: Uuid },
+}
+
+/// Submissions restored from journal.
+#[derive(Default)]
+pub struct Recovered {
+    pub queued: Vec<Submission>,
+    pub in_flight: Vec<Submission>,
+    pub lost: Vec<Submission>, // submissions which were accepted, but payload couldn't be read
+}
+
+enum Command {
+    Append {
+        bytes: Vec<u8>,
+        completes: bool,
+        written: Option<oneshot::Sender<Result<(), String>>>, // without it write errors are only logged
+    },
+    Compact,
+}
+
+/// Append-only write-ahead journal of submissions queue.
+/// It's a handle of writer thread, so disk I/O never blocks tasks holding the server lock.
+#[derive(Clone)]
+pub struct Journal {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+/// Owns journal file, records are written in the same order they were sent to `Journal`.
+struct Writer {
+    path: PathBuf,
+    file: File,
+    completed_since_compaction: usize,
+}
+
+impl Journal {
+    pub fn open(path: impl Into<PathBuf>) -> Result<(Self, Recovered), String> {
+        let path = path.into();
+        let recovered = if path.exists() {
+            Writer::replay(&path)?
+        } else {
+            Recovered::default()
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&path)
+            .map_err(|err| format!("Can't open journal {}: {err}", path.display()))?;
+        let mut writer = Writer {
+            path,
+            file,
+            completed_since_compaction: 0,
+        };
+        writer.compact()?;
+        log::info!("journal: Opened | path = {} | queued = {} | in_flight = {} | lost = {}", writer.path.display(), recovered.queued.len(), recovered.in_flight.len(), recovered.lost.len());
+
+        let (commands, receiver) = mpsc::unbounded_channel();
+        std::thread::Builder::new().name("journal-writer".to_string()).spawn(move || writer.run(receiver))
+            .map_err(|err| format!("Can't start journal writer: {err}"))?;
+        Ok((Self { commands }, recovered))
+    }
+
+    fn send(&self, record: Record, data: &[u8], written: Option<oneshot::Sender<Result<(), String>>>) -> Result<(), String> {
+        let completes = matches!(record, Record::Complete { .. });
+        let bytes = Writer::encode(&record, data)?;
+        self.commands.send(Command::Append { bytes, completes, written }).map_err(|_| "Journal writer is stopped".to_string())
+    }
+
+    fn write(&self, record: Record, data: &[u8]) {
+        if let Err(err) = self.send(record, data, None) {
+            log::error!("journal: Couldn't write record | error = {}", err);
+        }
+    }
+
+    /// Resolves when submission is durably written, submission mustn't be accepted otherwise.
+    pub async fn enqueue(&self, submission: &Submission) -> Result<(), String> {
+        let (written, result) = oneshot::channel();
+        self.send(Record::Enqueue { submission: submission.clone() }, &submission.data, Some(written))?;
+        result.await.map_err(|_| "Journal writer is stopped".to_string())?
+    }
+
+    pub fn assign(&self, (origin, uuid): SubmissionKey, invoker: Uuid) {
+        self.write(Record::Assign { origin, uuid, invoker }, &[]);
+    }
+
+    pub fn requeue(&self, (origin, uuid): SubmissionKey, attempts: u8) {
+        self.write(Record::Requeue { origin, uuid, attempts }, &[]);
+    }
+
+    pub fn complete(&self, (origin, uuid): SubmissionKey) {
+        self.write(Record::Complete { origin, uuid }, &[]);
+    }
+
+    /// Asks writer to compact journal every compaction interval, it's done only if enough submissions were completed.
+    pub async fn compactor(server: Arc<Mutex<Server>>) {
+        let mut interval = tokio::time::interval(COMPACTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            let journal = server.lock().await.journal.clone();
+            if journal.commands.send(Command::Compact).is_err() {
+                log::error!("journal: Writer is stopped, compaction is impossible");
+                return;
+            }
+        }
+    }
+}
+
+impl Writer {
+    fn run(mut self, mut commands: mpsc::UnboundedReceiver<Command>) {
+        while let Some(command) = commands.blocking_recv() {
+            match command {
+                Command::Append { bytes, completes, written } => {
+                    let result = self.append(&bytes);
+                    if completes {
+                        self.completed_since_compaction += 1;
+                    }
+                    match written {
+                        Some(written) => {
+                            let _ = written.send(result);
+                        },
+                        None => if let Err(err) = result {
+                            log::error!("journal: Couldn't write record | error = {}", err);
+                        },
+                    }
+                },
+                Command::Compact => {
+                    if self.completed_since_compaction < COMPACTION_THRESHOLD {
+                        continue;
+                    }
+                    if let Err(err) = self.compact() {
+                        log::error!("journal: Compaction failed | error = {}", err);
+                    }
+                },
+            }
+        }
+    }
+
+    fn replay(path: &PathBuf) -> Result<Recovered, String> {
+        let mut bytes = Vec::new();
+        File::open(path).and_then(|mut file| file.read_to_end(&mut bytes))
+            .map_err(|err| format!("Can't read journal {}: {err}", path.display()))?;
+
+        let mut order: Vec<SubmissionKey> = Vec::new();
+        let mut submissions: HashMap<SubmissionKey, (Submission, bool)> = HashMap::new(); // (submission, is assigned)
+        let mut lost = Vec::new();
+        let mut data = &bytes[..];
+        while !data.is_empty() {
+            let Some((header, rest)) = Self::take_chunk(data, 4) else {
+                log::error!("journal: Truncated record header, rest of journal is skipped | bytes_left = {}", data.len());
+                lost.append(&mut Self::salvage(data));
+                break;
+            };
+            let record: Record = match serde_json::from_slice(header) {
+                Ok(record) => record,
+                Err(err) => {
+                    log::error!("journal: Unparseable record, rest of journal is skipped | error = {}", err);
+                    lost.append(&mut Self::salvage(data));
+                    break;
+                }
+            };
+            let payload = Self::take_chunk(rest, 8);
+            data = payload.map_or(&[][..], |(_, rest)| rest);
+            match record {
+                Record::Enqueue { mut submission } => {
+                    let Some((payload, _)) = payload else {
+                        log::error!("journal: Payload of submission is truncated | submission_uuid = {}", submission.uuid);
+                        lost.push(submission);
+                        break;
+                    };
+                    submission.data = payload.to_vec();
+                    let key = submission.key();
+                    if !submissions.contains_key(&key) {
+                        order.push(key.clone());
+                    }
+                    submissions.insert(key, (submission, false));
+                },
+                Record::Assign { origin, uuid, .. } => {
+                    if let Some((_, assigned)) = submissions.get_mut(&(origin, uuid)) {
+                        *assigned = true;
+                    }
+                },
+                Record::Requeue { origin, uuid, attempts } => {
+                    if let Some((submission, assigned)) = submissions.get_mut(&(origin, uuid)) {
+                        submission.attempts = attempts;
+                        *assigned = false;
+                    }
+                },
+                Record::Complete { origin, uuid } => {
+                    submissions.remove(&(origin, uuid));
+                },
+            }
+        }
+
+        let mut recovered = Recovered { lost, ..Default::default() };
+        for key in order {
+            match submissions.remove(&key) {
+                Some((submission, false)) => recovered.queued.push(submission),
+                Some((submission, true)) => recovered.in_flight.push(submission),
+                None => {},
+            }
+        }
+        Ok(recovered)
+    }
+
+    /// Finds records after torn one, submissions enqueued there and not completed are lost, because the rest of journal can't be trusted.
+    fn salvage(torn: &[u8]) -> Vec<Submission> {
+        let mut enqueued: Vec<Submission> = Vec::new();
+        let mut data = &torn[1..];
+        while !data.is_empty() {
+            let record = Self::take_chunk(data, 4)
+                .filter(|(header, _)| header.first() == Some(&b'{'))
+                .and_then(|(header, rest)| Some((serde_json::from_slice::<Record>(header).ok()?, rest)));
+            let Some((record, rest)) = record else {
+                data = &data[1..];
+                continue;
+            };
+            data = Self::take_chunk(rest, 8).map_or(&[][..], |(_, rest)| rest);
+            match record {
+                Record::Enqueue { submission } => enqueued.push(submission),
+                Record::Complete { origin, uuid } => enqueued.retain(|submission| submission.key() != (origin.clone(), uuid)),
+                _ => {},
+            }
+        }
+        for submission in &enqueued {
+            log::error!("journal: Submission was written after torn record, it's lost | submission_uuid = {}", submission.uuid);
+        }
+        enqueued
+    }
+
+    fn take_chunk(data: &[u8], length_size: usize) -> Option<(&[u8], &[u8])> {
+        if data.len() < length_size {
+            return None;
+        }
+        let (length, rest) = data.split_at(length_size);
+        let length = length.iter().fold(0usize, |acc, &byte| (acc << 8) | byte as usize);
+        if rest.len() < length {
+            return None;
+        }
+        Some(rest.split_at(length))
+    }
+
+    fn encode(record: &Record, data: &[u8]) -> Result<Vec<u8>, String> {
+        let header = serde_json::to_vec(record).map_err(|err| err.to_string())?;
+        let mut bytes = Vec::with_capacity(header.len() + data.len() + 12);
+        bytes.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(&(data.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(data);
+        Ok(bytes)
+    }
+
+    /// Partially written record is cut off, otherwise records appended after it couldn't be replayed.
+    fn append(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let length = self.file.metadata().map_err(|err| format!("Can't read journal {}: {err}", self.path.display()))?.len();
+        self.file.write_all(bytes).and_then(|_| self.file.sync_data()).map_err(|err| {
+            if let Err(err) = self.file.set_len(length) {
+                log::error!("journal: Couldn't cut off partially written record | error = {} | path = {}", err, self.path.display());
+            }
+            format!("Can't write to journal {}: {err}", self.path.display())
+        })
+    }
+
+    /// Rewrites journal so it contains only not completed submissions.
+    fn compact(&mut self) -> Result<(), String> {
+        let recovered = Self::replay(&self.path)?;
+        let tmp_path = self.path.with_extension("compacting");
+        let mut bytes = Vec::new();
+        for submission in recovered.queued.iter().chain(recovered.in_flight.iter()) {
+            bytes.append(&mut Self::encode(&Record::Enqueue { submission: submission.clone() }, &submission.data)?);
+        }
+        for submission in recovered.in_flight.iter() {
+            bytes.append(&mut Self::encode(&Record::Assign { origin: submission.origin.clone(), uuid: submission.uuid, invoker: Uuid::nil() }, &[])?);
+        }
+        fs::write(&tmp_path, &bytes)
+            .and_then(|_| File::open(&tmp_path)?.sync_all())
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|err| format!("Can't compact journal {}: {err}", self.path.display()))?;
+        self.file = OpenOptions::new().append(true).open(&self.path)
+            .map_err(|err| format!("Can't reopen journal {}: {err}", self.path.display()))?;
+        self.completed_since_compaction = 0;
+        log::info!("journal: Compacted | path = {} | submissions = {}", self.path.display(), recovered.queued.len() + recovered.in_flight.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn submission(uuid: u128) -> Submission {
+        let mut submission = Submission::new(Uuid::from_u128(uuid), vec![uuid as u8; 4], 2);
+        submission.origin = "ts".to_string();
+        submission
+    }
+
+    fn enqueue(submission: &Submission) -> Vec<u8> {
+        Writer::encode(&Record::Enqueue { submission: submission.clone() }, &submission.data).unwrap()
+    }
+
+    fn replay(name: &str, bytes: &[u8]) -> Recovered {
+        let path = std::env::temp_dir().join(format!("invoker-manager-{}-{name}.journal", std::process::id()));
+        fs::write(&path, bytes).unwrap();
+        let recovered = Writer::replay(&path);
+        fs::remove_file(&path).unwrap();
+        recovered.unwrap()
+    }
+
+    fn uuids(submissions: &[Submission]) -> Vec<u128> {
+        submissions.iter().map(|submission| submission.uuid.as_u128()).collect()
+    }
+
+    #[test]
+    fn replay_restores_queued_and_in_flight_submissions() {
+        let mut bytes = [1, 2, 3, 4].map(submission).iter().flat_map(enqueue).collect::<Vec<_>>();
+        for record in [
+            Record::Assign { origin: "ts".to_string(), uuid: Uuid::from_u128(2), invoker: Uuid::nil() },
+            Record::Assign { origin: "ts".to_string(), uuid: Uuid::from_u128(3), invoker: Uuid::nil() },
+            Record::Requeue { origin: "ts".to_string(), uuid: Uuid::from_u128(3), attempts: 1 },
+            Record::Complete { origin: "ts".to_string(), uuid: Uuid::from_u128(4) },
+            Record::Complete { origin: "other".to_string(), uuid: Uuid::from_u128(1) },
+        ] {
+            bytes.append(&mut Writer::encode(&record, &[]).unwrap());
+        }
+        let recovered = replay("restore", &bytes);
+
+        assert_eq!(uuids(&recovered.queued), vec![1, 3]);
+        assert_eq!(uuids(&recovered.in_flight), vec![2]);
+        assert!(recovered.lost.is_empty());
+        assert_eq!(recovered.queued[0].data, vec![1; 4]);
+        assert_eq!(recovered.queued[1].attempts, 1);
+    }
+
+    #[test]
+    fn replay_reports_submissions_after_torn_record_as_lost() {
+        let torn = enqueue(&submission(2));
+        let mut bytes = enqueue(&submission(1));
+        bytes.extend_from_slice(&torn[..torn.len() / 2]);
+        bytes.append(&mut enqueue(&submission(3)));
+        bytes.append(&mut enqueue(&submission(4)));
+        bytes.append(&mut Writer::encode(&Record::Complete { origin: "ts".to_string(), uuid: Uuid::from_u128(4) }, &[]).unwrap());
+        let recovered = replay("torn", &bytes);
+
+        assert_eq!(uuids(&recovered.queued), vec![1]);
+        assert_eq!(uuids(&recovered.lost), vec![3]);
+    }
+
+    #[test]
+    fn replay_reports_submission_with_truncated_payload_as_lost() {
+        let bytes = enqueue(&submission(1));
+        let recovered = replay("truncated", &bytes[..bytes.len() - 1]);
+
+        assert!(recovered.queued.is_empty());
+        assert_eq!(uuids(&recovered.lost), vec![1]);
+    }
+}
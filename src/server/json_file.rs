@@ -0,0 +1,63 @@
+use std::{fs::{self, File}, path::PathBuf, sync::{Arc, Mutex}};
+
+use serde::Serialize;
+
+/// JSON file which is written outside of the server lock: changes are applied in memory and their snapshots are saved later.
+/// Snapshots are saved one at a time and the ones older than already saved are skipped, so file doesn't go back in time.
+pub struct JsonFile {
+    name: &'static str, // what is kept in the file, it's used in errors
+    path: PathBuf,
+    taken: u64, // version of the last taken snapshot
+    saved: Arc<Mutex<u64>>, // version of the last saved snapshot
+}
+
+pub struct Snapshot {
+    name: &'static str,
+    path: PathBuf,
+    content: String,
+    version: u64,
+    saved: Arc<Mutex<u64>>,
+}
+
+impl JsonFile {
+    pub fn new(name: &'static str, path: PathBuf) -> Self {
+        Self {
+            name,
+            path,
+            taken: 0,
+            saved: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    pub fn snapshot(&mut self, value: &impl Serialize) -> Result<Snapshot, String> {
+        let content = serde_json::to_string_pretty(value).map_err(|err| format!("Can't serialize {}: {err}", self.name))?;
+        self.taken += 1;
+        Ok(Snapshot {
+            name: self.name,
+            path: self.path.clone(),
+            content,
+            version: self.taken,
+            saved: self.saved.clone(),
+        })
+    }
+}
+
+impl Snapshot {
+    pub async fn save(self) -> Result<(), String> {
+        tokio::task::spawn_blocking(move || self.write()).await.map_err(|err| err.to_string())?
+    }
+
+    fn write(self) -> Result<(), String> {
+        let mut saved = self.saved.lock().unwrap();
+        if *saved >= self.version {
+            return Ok(()); // newer snapshot is already saved
+        }
+        let tmp_path = self.path.with_extension("saving");
+        fs::write(&tmp_path, &self.content)
+            .and_then(|_| File::open(&tmp_path)?.sync_all())
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|err| format!("Can't save {} {}: {err}", self.name, self.path.display()))?;
+        *saved = self.version;
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use std::{collections::{BTreeMap, HashMap}, fmt::Write, sync::{atomic::{AtomicU64, Ordering}, Mutex}, time::{Duration, Instant}};
+use super::submission::SubmissionKey;
+
+const QUEUE_WAIT_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0];
+const TESTING_LATENCY_BUCKETS: &[f64] = &[1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0];
+
+/// Histogram with fixed buckets, values are in seconds.
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: Duration) {
+        let value = value.as_secs_f64();
+        for (bucket, count) in self.buckets.iter().zip(self.counts.iter_mut()) {
+            if value <= *bucket {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bucket, count) in self.buckets.iter().zip(self.counts.iter()) {
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bucket}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum {}", self.sum);
+        let _ = writeln!(out, "{name}_count {}", self.count);
+    }
+}
+
+/// Gauges which are computed from the server state when metrics are scraped.
+pub struct Snapshot {
+    pub queue_depth: usize,
+    pub invokers_connected: usize,
+    pub invokers_busy: usize,
+}
+
+/// Counters and histograms of `invoker-manager`, rendered in Prometheus text format.
+pub struct Metrics {
+    submissions_received: AtomicU64,
+    submissions_completed: Mutex<BTreeMap<String, u64>>, // by verdict
+    test_verdicts: Mutex<BTreeMap<String, u64>>, // by verdict
+    authorisation_failures: Mutex<BTreeMap<String, u64>>, // by authorisation method
+    testing_systems_connected: Mutex<BTreeMap<String, bool>>, // by origin
+    protocol_errors: Mutex<BTreeMap<String, u64>>, // by peer
+    queue_wait: Mutex<Histogram>,
+    testing_latency: Mutex<Histogram>,
+    received_at: Mutex<HashMap<SubmissionKey, Instant>>, // submissions which haven't got verdict yet
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            submissions_received: AtomicU64::new(0),
+            submissions_completed: Mutex::new(BTreeMap::new()),
+            test_verdicts: Mutex::new(BTreeMap::new()),
+            authorisation_failures: Mutex::new(BTreeMap::new()),
+            testing_systems_connected: Mutex::new(BTreeMap::new()),
+            protocol_errors: Mutex::new(BTreeMap::new()),
+            queue_wait: Mutex::new(Histogram::new(QUEUE_WAIT_BUCKETS)),
+            testing_latency: Mutex::new(Histogram::new(TESTING_LATENCY_BUCKETS)),
+            received_at: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn submission_received(&self, key: SubmissionKey) {
+        self.submissions_received.fetch_add(1, Ordering::Relaxed);
+        self.received_at.lock().unwrap().insert(key, Instant::now());
+    }
+
+    /// Counts submission verdict and observes time since submission was received, if it was received after start.
+    pub fn submission_completed(&self, key: SubmissionKey, verdict: &str) {
+        *self.submissions_completed.lock().unwrap().entry(verdict.to_string()).or_insert(0) += 1;
+        if let Some(received_at) = self.received_at.lock().unwrap().remove(&key) {
+            self.testing_latency.lock().unwrap().observe(received_at.elapsed());
+        }
+    }
+
+    pub fn test_verdict(&self, verdict: &str) {
+        *self.test_verdicts.lock().unwrap().entry(verdict.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn authorisation_failed(&self, method: &str) {
+        *self.authorisation_failures.lock().unwrap().entry(method.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn protocol_error(&self, peer: &str) {
+        *self.protocol_errors.lock().unwrap().entry(peer.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn testing_system_connected(&self, origin: &str, connected: bool) {
+        self.testing_systems_connected.lock().unwrap().insert(origin.to_string(), connected);
+    }
+
+    pub fn queue_waited(&self, waited: Duration) {
+        self.queue_wait.lock().unwrap().observe(waited);
+    }
+
+    pub fn render(&self, snapshot: Snapshot) -> String {
+        let mut out = String::new();
+        Self::render_gauge(&mut out, "invoker_manager_queue_depth", "Submissions waiting in the queue.", snapshot.queue_depth);
+        Self::render_gauge(&mut out, "invoker_manager_invokers_connected", "Connected invokers.", snapshot.invokers_connected);
+        Self::render_gauge(&mut out, "invoker_manager_invokers_busy", "Invokers testing submission.", snapshot.invokers_busy);
+        Self::render_gauge(&mut out, "invoker_manager_invokers_idle", "Invokers waiting for submission.", snapshot.invokers_connected - snapshot.invokers_busy);
+
+        let _ = writeln!(out, "# HELP invoker_manager_submissions_received_total Submissions received from testing systems.");
+        let _ = writeln!(out, "# TYPE invoker_manager_submissions_received_total counter");
+        let _ = writeln!(out, "invoker_manager_submissions_received_total {}", self.submissions_received.load(Ordering::Relaxed));
+
+        Self::render_labeled(&mut out, "invoker_manager_submissions_completed_total", "Submission verdicts sent to testing systems.", "counter", "verdict",
+            self.submissions_completed.lock().unwrap().iter().map(|(verdict, count)| (verdict.clone(), *count)));
+        Self::render_labeled(&mut out, "invoker_manager_test_verdicts_total", "Test verdicts received from invokers.", "counter", "verdict",
+            self.test_verdicts.lock().unwrap().iter().map(|(verdict, count)| (verdict.clone(), *count)));
+        Self::render_labeled(&mut out, "invoker_manager_authorisation_failures_total", "Failed authorisations of invokers.", "counter", "method",
+            self.authorisation_failures.lock().unwrap().iter().map(|(method, count)| (method.clone(), *count)));
+        Self::render_labeled(&mut out, "invoker_manager_protocol_errors_total", "Malformed messages received from peers.", "counter", "peer",
+            self.protocol_errors.lock().unwrap().iter().map(|(peer, count)| (peer.clone(), *count)));
+        Self::render_labeled(&mut out, "invoker_manager_testing_system_connected", "Whether connection to testing system is open.", "gauge", "origin",
+            self.testing_systems_connected.lock().unwrap().iter().map(|(origin, connected)| (origin.clone(), *connected as u64)));
+
+        self.queue_wait.lock().unwrap().render(&mut out, "invoker_manager_queue_wait_seconds", "Time submissions spent in the queue before invoker took them.");
+        self.testing_latency.lock().unwrap().render(&mut out, "invoker_manager_testing_latency_seconds", "Time from receiving submission to sending its verdict.");
+        out
+    }
+
+    fn render_gauge(out: &mut String, name: &str, help: &str, value: usize) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "{name} {value}");
+    }
+
+    fn render_labeled(out: &mut String, name: &str, help: &str, kind: &str, label: &str, values: impl Iterator<Item = (String, u64)>) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (value_label, value) in values {
+            let value_label = value_label.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(out, "{name}{{{label}=\"{value_label}\"}} {value}");
+        }
+    }
+}
@@ -1,43 +1,163 @@
 mod testing_system;
 mod invoker;
 pub mod authorisation;
+pub mod capabilities;
+pub mod events;
+pub mod journal;
+pub mod json_file;
+pub mod metrics;
+pub mod protocol;
 pub mod control_panel;
 pub mod verdict;
+pub mod settings;
 pub mod submission;
+pub mod submissions_pool;
 pub mod invokers_side;
+pub mod invokers_registry;
 pub mod testing_system_side;
+pub mod tls;
 
 use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::Mutex;
 use uuid::Uuid;
-use authorisation::Authorisation;
+use authorisation::{revocations::Revocations, Authorisation};
+use events::{Event, EventBus};
+use journal::{Journal, Recovered};
+use metrics::Metrics;
 use invokers_side::InvokersSide;
+use invokers_registry::InvokersRegistry;
+use invoker::Invoker;
 use testing_system_side::TestingSystemSide;
-use submission::Submission;
-use verdict::TestResult;
-
-const MAX_SUBMISSIONS_COUNT: usize = 10000;
+use settings::Settings;
+use submission::{Submission, SubmissionKey};
+use submissions_pool::SubmissionsPool;
+use verdict::{TestResult, Verdict};
 
 pub struct Server {
     pub authorisation: Authorisation,
+    pub revocations: Revocations,
     pub testing_system_side: TestingSystemSide,
     pub invokers_side: InvokersSide,
-    tests_results: HashMap<Uuid, Vec<TestResult>>,
+    pub invokers_registry: InvokersRegistry,
+    pub submissions_pool: Arc<SubmissionsPool>,
+    pub settings: Settings,
+    pub metrics: Arc<Metrics>,
+    pub events: EventBus,
+    tests_results: HashMap<SubmissionKey, Vec<TestResult>>,
+    journal: Journal,
 }
 
 impl Server {
-    pub fn new(authorisation: Authorisation) -> Arc<Mutex<Self>> {
-        let (sps, spr) = mpsc::channel::<Submission>(MAX_SUBMISSIONS_COUNT);
+    pub fn new(authorisation: Authorisation, revocations: Revocations, invokers_registry: InvokersRegistry, journal: Journal, settings: Settings) -> Arc<Mutex<Self>> {
         Arc::new(Mutex::new(Self {
             authorisation,
-            testing_system_side: TestingSystemSide::new(sps),
-            invokers_side: InvokersSide::new(spr),
+            revocations,
+            testing_system_side: TestingSystemSide::new(),
+            invokers_side: InvokersSide::new(),
+            invokers_registry,
+            submissions_pool: Arc::new(SubmissionsPool::new(settings.max_queued_submissions, settings.aging_step)),
+            settings,
+            metrics: Arc::new(Metrics::new()),
+            events: EventBus::new(),
             tests_results: HashMap::new(),
+            journal,
         }))
     }
 
-    async fn remove_tests_result(server: Arc<Mutex<Server>>, uuid: Uuid) -> Option<Vec<TestResult>> {
-        server.lock().await.tests_results.remove(&uuid)
+    /// Applies reloaded settings, running timers pick them up on their next tick.
+    pub async fn apply_settings(server: Arc<Mutex<Server>>, settings: Settings) {
+        let mut server_locked = server.lock().await;
+        if server_locked.settings == settings {
+            log::info!("Settings haven't changed");
+            return;
+        }
+        server_locked.submissions_pool.configure(settings.max_queued_submissions, settings.aging_step);
+        log::info!("Settings applied | settings = {:?}", settings);
+        server_locked.settings = settings;
     }
-}
 
+    /// Returns submissions restored from journal to the queue.
+    pub async fn recover(server: Arc<Mutex<Server>>, recovered: Recovered) {
+        let submissions_pool = server.lock().await.submissions_pool.clone();
+        for submission in recovered.queued {
+            let (submission_uuid, tests_count, origin) = (submission.uuid, submission.tests_count, submission.origin.clone());
+            Self::reset_tests_result(server.clone(), submission.key(), tests_count).await;
+            if submissions_pool.push_back(submission).await.is_err() {
+                log::error!("Recovered submission doesn't fit into the queue | submission_uuid = {}", submission_uuid);
+                Self::fail_submission(server.clone(), submission_uuid, origin, "Submission couldn't be restored to queue after restart.".to_string()).await;
+            }
+        }
+        for submission in recovered.in_flight {
+            Self::requeue_submission(server.clone(), submission).await;
+        }
+        for submission in recovered.lost {
+            log::error!("Submission couldn't be recovered from journal | submission_uuid = {}", submission.uuid);
+            Self::fail_submission(server.clone(), submission.uuid, submission.origin, "Submission was lost while invoker-manager restarted.".to_string()).await;
+        }
+    }
+
+    /// Finishes submission, which isn't tested by any invoker, with given verdict.
+    pub async fn finish_submission(server: Arc<Mutex<Server>>, submission_uuid: Uuid, origin: String, verdict: Verdict, message: String) {
+        let key = (origin.clone(), submission_uuid);
+        let tests_results = Self::remove_tests_result(server.clone(), &key).await.unwrap_or_default();
+        server.lock().await.journal.complete(key);
+        tokio::spawn(TestingSystemSide::send_submission_verdict(server, origin, verdict, submission_uuid, tests_results, Err(message)));
+    }
+
+    /// Finishes submission with TE verdict.
+    pub async fn fail_submission(server: Arc<Mutex<Server>>, submission_uuid: Uuid, origin: String, message: String) {
+        Self::finish_submission(server, submission_uuid, origin, Verdict::TE, message).await;
+    }
+
+    /// Removes submission from the queue or stops invoker which is testing it, testing system gets SK verdict.
+    /// Without `origin` submission with given uuid from any testing system is cancelled.
+    pub async fn cancel_submission(server: Arc<Mutex<Server>>, origin: Option<&str>, submission_uuid: Uuid) -> Result<(), String> {
+        let submissions_pool = server.lock().await.submissions_pool.clone();
+        if let Some(submission) = submissions_pool.remove(origin, submission_uuid).await {
+            log::info!("Queued submission cancelled | submission_uuid = {}", submission_uuid);
+            Self::finish_submission(server, submission_uuid, submission.origin, Verdict::SK, "Submission was cancelled.".to_string()).await;
+            return Ok(());
+        }
+
+        let invokers: Vec<Arc<Mutex<Invoker>>> = server.lock().await.invokers_side.invokers.values().cloned().collect();
+        for invoker in invokers {
+            let is_cancelled = |submission: &Submission| submission.uuid == submission_uuid && origin.is_none_or(|origin| submission.origin == origin);
+            let Some((submission, tests_results)) = Invoker::stop_submission_if(server.clone(), invoker, is_cancelled).await else {
+                continue;
+            };
+            log::info!("Testing submission cancelled | submission_uuid = {}", submission_uuid);
+            tokio::spawn(TestingSystemSide::send_submission_verdict(server, submission.origin, Verdict::SK, submission_uuid, tests_results, Err("Submission was cancelled.".to_string())));
+            return Ok(());
+        }
+        Err(format!("Submission {submission_uuid} isn't queued or testing"))
+    }
+
+    async fn remove_tests_result(server: Arc<Mutex<Server>>, key: &SubmissionKey) -> Option<Vec<TestResult>> {
+        server.lock().await.tests_results.remove(key)
+    }
+
+    async fn reset_tests_result(server: Arc<Mutex<Server>>, key: SubmissionKey, tests_count: u16) {
+        server.lock().await.tests_results.insert(key, vec![TestResult::new(); tests_count as usize]);
+    }
+
+    /// Puts submission lost or timed out by invoker back to the head of the queue, or fails it with TE if it was lost too many times.
+    pub async fn requeue_submission(server: Arc<Mutex<Server>>, mut submission: Submission) {
+        submission.attempts += 1;
+        let max_retries = server.lock().await.settings.max_submission_retries;
+        if submission.attempts > max_retries {
+            log::error!("Submission exceeded retries limit | submission_uuid = {} | attempts = {}", submission.uuid, submission.attempts);
+            let message = format!("Invokers failed to test submission {} times, retries limit is {}", submission.attempts, max_retries);
+            Self::fail_submission(server, submission.uuid, submission.origin, message).await;
+            return;
+        }
+        log::info!("Submission requeued | submission_uuid = {} | attempts = {}", submission.uuid, submission.attempts);
+        server.lock().await.journal.requeue(submission.key(), submission.attempts);
+        Self::reset_tests_result(server.clone(), submission.key(), submission.tests_count).await;
+        let submissions_pool = {
+            let server_locked = server.lock().await;
+            server_locked.events.publish(Event::SubmissionQueued { submission_uuid: submission.uuid, tests_count: submission.tests_count, priority: submission.priority, attempts: submission.attempts });
+            server_locked.submissions_pool.clone()
+        };
+        submissions_pool.push_front(submission).await;
+    }
+}
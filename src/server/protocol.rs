@@ -0,0 +1,266 @@
+use std::{collections::{HashMap, VecDeque}, str::FromStr, time::{Duration, Instant}};
+
+use ratchet_rs::SubprotocolRegistry;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::verdict::Verdict;
+
+/// Version of invokers' protocol, it's negotiated as WebSocket subprotocol, invokers which don't offer any speak `V1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum InvokerProtocol {
+    #[serde(rename = "invoker.v1")]
+    V1, // START carries only data, messages don't echo assignment
+    #[serde(rename = "invoker.v2")]
+    V2, // START carries SUBMISSION and NONCE, TEST, VERDICT and OPERROR have to echo them
+}
+
+impl InvokerProtocol {
+    const SUPPORTED: [Self; 2] = [Self::V2, Self::V1]; // the newest is preferred
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::V1 => "invoker.v1",
+            Self::V2 => "invoker.v2",
+        }
+    }
+
+    pub fn registry() -> SubprotocolRegistry {
+        SubprotocolRegistry::new(Self::SUPPORTED.map(Self::name)).expect("invoker subprotocols are valid")
+    }
+
+    pub fn negotiated(subprotocol: Option<&String>) -> Self {
+        Self::SUPPORTED.into_iter().find(|protocol| subprotocol.is_some_and(|subprotocol| subprotocol == protocol.name())).unwrap_or(Self::V1)
+    }
+}
+
+/// Version of testing systems' protocol, it's offered as WebSocket subprotocol, testing systems which don't choose any speak `V1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum TestingSystemProtocol {
+    #[serde(rename = "testing-system.v1")]
+    V1,
+}
+
+impl TestingSystemProtocol {
+    const SUPPORTED: [Self; 1] = [Self::V1];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::V1 => "testing-system.v1",
+        }
+    }
+
+    pub fn registry() -> SubprotocolRegistry {
+        SubprotocolRegistry::new(Self::SUPPORTED.map(Self::name)).expect("testing system subprotocols are valid")
+    }
+
+    pub fn negotiated(subprotocol: Option<&String>) -> Self {
+        Self::SUPPORTED.into_iter().find(|protocol| subprotocol.is_some_and(|subprotocol| subprotocol == protocol.name())).unwrap_or(Self::V1)
+    }
+}
+
+/// Message which peer sent, but which can't be parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    MissingHeader(&'static str),
+    BadNumber { header: &'static str, value: String },
+    BadUuid { header: &'static str, value: String },
+    UnknownVerdict(String),
+    UnknownType(String),
+    TruncatedFrame { expected: usize, length: usize },
+    GroupsMismatch { header: &'static str, expected: usize, got: usize },
+    InconsistentScore(String),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingHeader(header) => write!(f, "Message doesn't contain {header} header"),
+            Self::BadNumber { header, value } => write!(f, "Can't parse {header} header as number: {value}"),
+            Self::BadUuid { header, value } => write!(f, "Can't parse {header} header as uuid: {value}"),
+            Self::UnknownVerdict(verdict) => write!(f, "Unknown verdict {verdict}"),
+            Self::UnknownType(message_type) => write!(f, "Unknown message type {message_type}"),
+            Self::TruncatedFrame { expected, length } => write!(f, "Frame is truncated, expected at least {expected} bytes, got {length}"),
+            Self::GroupsMismatch { header, expected, got } => write!(f, "{header} header should have {expected} groups, got {got}"),
+            Self::InconsistentScore(reason) => write!(f, "Inconsistent score: {reason}"),
+        }
+    }
+}
+
+pub fn header<'a>(headers: &'a HashMap<String, String>, header: &'static str) -> Result<&'a str, ProtocolError> {
+    headers.get(header).map(String::as_str).ok_or(ProtocolError::MissingHeader(header))
+}
+
+pub fn number<T: FromStr>(headers: &HashMap<String, String>, header: &'static str) -> Result<T, ProtocolError> {
+    let value = self::header(headers, header)?;
+    T::from_str(value).map_err(|_| ProtocolError::BadNumber { header, value: value.to_string() })
+}
+
+pub fn optional_number<T: FromStr>(headers: &HashMap<String, String>, header: &'static str) -> Result<Option<T>, ProtocolError> {
+    headers.contains_key(header).then(|| number(headers, header)).transpose()
+}
+
+pub fn optional_uuid(headers: &HashMap<String, String>, header: &'static str) -> Result<Option<Uuid>, ProtocolError> {
+    headers.get(header).map(|value| Uuid::from_str(value).map_err(|_| ProtocolError::BadUuid { header, value: value.clone() })).transpose()
+}
+
+fn parse_points(header: &'static str, value: &str) -> Result<f64, ProtocolError> {
+    f64::from_str(value).ok().filter(|points| points.is_finite()).ok_or_else(|| ProtocolError::BadNumber { header, value: value.to_string() })
+}
+
+fn parse_verdict(value: &str) -> Result<Verdict, ProtocolError> {
+    match Verdict::from(&value.to_string()) {
+        Verdict::UV => Err(ProtocolError::UnknownVerdict(value.to_string())),
+        verdict => Ok(verdict),
+    }
+}
+
+/// Points have to be finite, `NaN` and infinities aren't accepted.
+pub fn points(headers: &HashMap<String, String>, header: &'static str) -> Result<f64, ProtocolError> {
+    parse_points(header, self::header(headers, header)?)
+}
+
+/// Space separated points of every group.
+pub fn groups_points(headers: &HashMap<String, String>, header: &'static str) -> Result<Vec<f64>, ProtocolError> {
+    self::header(headers, header)?.split_whitespace().map(|points| parse_points(header, points)).collect()
+}
+
+/// Same as `groups_points`, but header may be absent and has to contain exactly `groups` values.
+pub fn optional_groups_points(headers: &HashMap<String, String>, header: &'static str, groups: usize) -> Result<Option<Vec<f64>>, ProtocolError> {
+    let Some(value) = headers.get(header) else {
+        return Ok(None);
+    };
+    let points = value.split_whitespace().map(|points| parse_points(header, points)).collect::<Result<Vec<_>, _>>()?;
+    groups_count(header, groups, points.len())?;
+    Ok(Some(points))
+}
+
+/// Space separated verdicts of every group, header may be absent and has to contain exactly `groups` values.
+pub fn optional_groups_verdicts(headers: &HashMap<String, String>, header: &'static str, groups: usize) -> Result<Option<Vec<Verdict>>, ProtocolError> {
+    let Some(value) = headers.get(header) else {
+        return Ok(None);
+    };
+    let verdicts = value.split_whitespace().map(parse_verdict).collect::<Result<Vec<_>, _>>()?;
+    groups_count(header, groups, verdicts.len())?;
+    Ok(Some(verdicts))
+}
+
+/// Relative difference of `SUM` and total of `GROUPS` which is put down to rounding of decimal points.
+const SUM_TOLERANCE: f64 = 1e-6;
+
+/// Points can't be negative, `SUM` has to be the total of `GROUPS` and group can't get more than its `GROUPS_MAX`.
+pub fn check_score(sum: f64, points: &[f64], max_points: Option<&[f64]>) -> Result<(), ProtocolError> {
+    if let Some(points) = std::iter::once(&sum).chain(points).find(|points| **points < 0.0) {
+        return Err(ProtocolError::InconsistentScore(format!("points can't be negative, got {points}")));
+    }
+    let total: f64 = points.iter().sum();
+    if (sum - total).abs() > SUM_TOLERANCE * sum.abs().max(total.abs()).max(1.0) {
+        return Err(ProtocolError::InconsistentScore(format!("SUM is {sum}, but GROUPS sum up to {total}")));
+    }
+    let exceeded = max_points.and_then(|max_points| points.iter().zip(max_points).enumerate().find(|(_, (points, max_points))| points > max_points));
+    if let Some((group, (points, max_points))) = exceeded {
+        return Err(ProtocolError::InconsistentScore(format!("group {group} got {points} points, but its max is {max_points}")));
+    }
+    Ok(())
+}
+
+fn groups_count(header: &'static str, expected: usize, got: usize) -> Result<(), ProtocolError> {
+    if expected != got {
+        return Err(ProtocolError::GroupsMismatch { header, expected, got });
+    }
+    Ok(())
+}
+
+/// `UV` isn't accepted from peers, it's used only for verdicts which can't be parsed.
+pub fn verdict(headers: &HashMap<String, String>, header: &'static str) -> Result<Verdict, ProtocolError> {
+    parse_verdict(self::header(headers, header)?)
+}
+
+/// Malformed messages of peer, peer is disconnected if it sends more than `budget` of them in `window`.
+#[derive(Debug, Default)]
+pub struct ErrorBudget {
+    total: u64,
+    recent: VecDeque<Instant>,
+}
+
+impl ErrorBudget {
+    /// Returns `false` when budget is exceeded.
+    pub fn spend(&mut self, budget: u32, window: Duration) -> bool {
+        self.total += 1;
+        self.recent.push_back(Instant::now());
+        while self.recent.front().is_some_and(|at| at.elapsed() > window) {
+            self.recent.pop_front();
+        }
+        self.recent.len() <= budget as usize
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn bad_numbers_are_rejected() {
+        let headers = headers(&[("TIME", "12ms"), ("MEMORY", "-1"), ("SUM", "NaN"), ("GROUPS", "1 inf")]);
+        assert_eq!(number::<u64>(&headers, "TIME"), Err(ProtocolError::BadNumber { header: "TIME", value: "12ms".to_string() }));
+        assert_eq!(number::<u64>(&headers, "MEMORY"), Err(ProtocolError::BadNumber { header: "MEMORY", value: "-1".to_string() }));
+        assert_eq!(points(&headers, "SUM"), Err(ProtocolError::BadNumber { header: "SUM", value: "NaN".to_string() }));
+        assert_eq!(groups_points(&headers, "GROUPS"), Err(ProtocolError::BadNumber { header: "GROUPS", value: "inf".to_string() }));
+        assert_eq!(number::<u64>(&headers, "ID"), Err(ProtocolError::MissingHeader("ID")));
+        assert_eq!(optional_number::<u64>(&headers, "ID"), Ok(None));
+    }
+
+    #[test]
+    fn unknown_verdicts_are_rejected() {
+        let headers = headers(&[("VERDICT", "XX"), ("UNPARSED", "UV"), ("GROUPS_VERDICTS", "OK QQ")]);
+        assert_eq!(verdict(&headers, "VERDICT").unwrap_err(), ProtocolError::UnknownVerdict("XX".to_string()));
+        assert_eq!(verdict(&headers, "UNPARSED").unwrap_err(), ProtocolError::UnknownVerdict("UV".to_string()));
+        assert_eq!(optional_groups_verdicts(&headers, "GROUPS_VERDICTS", 2).unwrap_err(), ProtocolError::UnknownVerdict("QQ".to_string()));
+    }
+
+    #[test]
+    fn groups_count_has_to_match() {
+        let headers = headers(&[("GROUPS_MAX", "10 20 30"), ("GROUPS_VERDICTS", "OK WA")]);
+        assert_eq!(optional_groups_points(&headers, "GROUPS_MAX", 2), Err(ProtocolError::GroupsMismatch { header: "GROUPS_MAX", expected: 2, got: 3 }));
+        assert_eq!(optional_groups_points(&headers, "GROUPS_MAX", 3), Ok(Some(vec![10.0, 20.0, 30.0])));
+        assert_eq!(optional_groups_verdicts(&headers, "GROUPS_VERDICTS", 3).unwrap_err(), ProtocolError::GroupsMismatch { header: "GROUPS_VERDICTS", expected: 3, got: 2 });
+        assert_eq!(optional_groups_points(&headers, "MISSING", 3), Ok(None));
+    }
+
+    #[test]
+    fn inconsistent_scores_are_rejected() {
+        assert_eq!(check_score(30.0, &[10.0, 20.0], Some(&[10.0, 25.0])), Ok(()));
+        assert_eq!(check_score(0.3, &[0.1, 0.2], None), Ok(()));
+        assert_eq!(check_score(0.0, &[], None), Ok(()));
+        assert!(matches!(check_score(-1.0, &[-1.0], None), Err(ProtocolError::InconsistentScore(_))));
+        assert!(matches!(check_score(0.0, &[5.0, -5.0], None), Err(ProtocolError::InconsistentScore(_))));
+        assert!(matches!(check_score(31.0, &[10.0, 20.0], None), Err(ProtocolError::InconsistentScore(_))));
+        assert!(matches!(check_score(30.0, &[10.0, 20.0], Some(&[10.0, 15.0])), Err(ProtocolError::InconsistentScore(_))));
+    }
+
+    #[test]
+    fn budget_is_exhausted_within_window() {
+        let mut budget = ErrorBudget::default();
+        assert!(budget.spend(2, Duration::from_secs(60)));
+        assert!(budget.spend(2, Duration::from_secs(60)));
+        assert!(!budget.spend(2, Duration::from_secs(60)));
+        assert_eq!(budget.total(), 3);
+    }
+
+    #[test]
+    fn budget_recovers_after_window() {
+        let mut budget = ErrorBudget::default();
+        assert!(budget.spend(1, Duration::from_millis(5)));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(budget.spend(1, Duration::from_millis(5)));
+        assert_eq!(budget.total(), 2);
+    }
+}
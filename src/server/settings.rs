@@ -0,0 +1,22 @@
+use std::time::Duration;
+use super::{authorisation::cert_cache::CertCachePolicy, invokers_registry::DuplicateSession, submission::DeadlinePolicy};
+
+/// Settings which can be changed while `invoker-manager` is running, websocket settings are used for new connections.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub max_message_size: usize,
+    pub compression_level: u32,
+    pub max_queued_submissions: usize,
+    pub max_submission_retries: u8,
+    pub deadline_policy: DeadlinePolicy,
+    pub aging_step: Duration,
+    pub unroutable_timeout: Duration,
+    pub invokers_ping_interval: Duration,
+    pub invokers_ping_timeout: Duration,
+    pub duplicate_session: DuplicateSession,
+    pub testing_systems_ping_interval: Duration,
+    pub keys_reload_interval: Duration,
+    pub cert_cache: CertCachePolicy,
+    pub protocol_error_budget: u32,
+    pub protocol_error_window: Duration,
+}
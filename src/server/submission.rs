@@ -1,16 +1,62 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
+use super::capabilities::Requirements;
 
 #[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize)]
 pub struct Submission {
     pub uuid: Uuid,
     pub tests_count: u16,
+    #[serde(skip)]
     pub data: Vec<u8>,
+    pub attempts: u8, // how many times invokers have lost this submission
+    #[serde(default)]
+    pub priority: i32, // submissions with bigger priority are tested first
+    #[serde(default)]
+    pub requirements: Requirements,
+    pub origin: String, // testing system which sent this submission
+    #[serde(default)]
+    pub deadline: Option<u64>, // seconds, explicitly set by testing system
+    #[serde(skip)]
+    pub enqueued_at: Option<Instant>, // when submission was queued for the first time, requeues keep it
+}
+
+/// Longer deadlines are cut to it, testing can't reasonably take longer.
+pub const MAX_DEADLINE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Origin and uuid of submission, testing systems don't coordinate uuids, so uuid alone isn't unique.
+pub type SubmissionKey = (String, Uuid);
+
+/// How long invoker may test submission if testing system didn't set deadline explicitly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeadlinePolicy {
+    pub base: Duration,
+    pub per_test: Duration,
 }
 
 impl Submission {
     pub fn new(uuid: Uuid, data: Vec<u8>, tests_count: u16) -> Self {
         Self {
             uuid, data, tests_count,
+            attempts: 0,
+            priority: 0,
+            requirements: Requirements::default(),
+            origin: String::new(),
+            deadline: None,
+            enqueued_at: None,
         }
     }
+
+    pub fn key(&self) -> SubmissionKey {
+        (self.origin.clone(), self.uuid)
+    }
+
+    pub fn deadline(&self, policy: &DeadlinePolicy) -> Duration {
+        let deadline = match self.deadline {
+            Some(deadline) => Duration::from_secs(deadline),
+            None => policy.base.saturating_add(policy.per_test.saturating_mul(self.tests_count as u32)),
+        };
+        deadline.min(MAX_DEADLINE)
+    }
 }
@@ -0,0 +1,167 @@
+use std::{collections::BTreeMap, sync::atomic::{AtomicU64, AtomicUsize, Ordering}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use serde::Serialize;
+use tokio::sync::{Mutex, Notify};
+use uuid::Uuid;
+use super::{capabilities::Capabilities, submission::Submission};
+
+struct Entry {
+    submission: Submission,
+    enqueued_at: Instant, // the first time submission was queued, it's used for aging
+    queued_at: Instant, // the last time submission was queued
+    requeued: bool,
+}
+
+impl Entry {
+    fn new(mut submission: Submission, requeued: bool) -> Self {
+        let queued_at = Instant::now();
+        Self {
+            enqueued_at: *submission.enqueued_at.get_or_insert(queued_at),
+            submission,
+            queued_at,
+            requeued,
+        }
+    }
+
+    /// Entries with bigger key are taken first, waiting for `aging_step` raises effective priority by one, so low-priority submissions aren't starved.
+    fn key(&self, now: Instant, aging_step: Duration) -> (i64, bool, Duration) {
+        let waited = now.saturating_duration_since(self.enqueued_at);
+        let aging = (waited.as_millis() / aging_step.as_millis().max(1)) as i64;
+        (self.submission.priority as i64 + aging, self.requeued, waited)
+    }
+}
+
+/// Queued submission as it is shown in control panel.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueuedSubmission {
+    pub uuid: Uuid,
+    pub tests_count: u16,
+    pub data_size: usize,
+    pub priority: i32,
+    pub attempts: u8,
+    pub enqueued_at: f64, // unix time in seconds
+    pub waited: f64, // seconds
+}
+
+/// Priority queue of submissions waiting for a free invoker.
+pub struct SubmissionsPool {
+    queue: Mutex<Vec<Entry>>,
+    capacity: AtomicUsize,
+    aging_step: AtomicU64, // milliseconds
+    notify: Notify,
+}
+
+impl SubmissionsPool {
+    pub fn new(capacity: usize, aging_step: Duration) -> Self {
+        Self {
+            queue: Mutex::new(Vec::new()),
+            capacity: AtomicUsize::new(capacity),
+            aging_step: AtomicU64::new(aging_step.as_millis() as u64),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Changes limits of the queue, submissions which are already queued are kept even if they don't fit.
+    pub fn configure(&self, capacity: usize, aging_step: Duration) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+        self.aging_step.store(aging_step.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn aging_step(&self) -> Duration {
+        Duration::from_millis(self.aging_step.load(Ordering::Relaxed))
+    }
+
+    /// Adds new submission to the queue, returns it back if queue is full.
+    pub async fn push_back(&self, submission: Submission) -> Result<(), Submission> {
+        let mut queue = self.queue.lock().await;
+        if queue.len() >= self.capacity.load(Ordering::Relaxed) {
+            return Err(submission);
+        }
+        queue.push(Entry::new(submission, false));
+        self.notify.notify_waiters();
+        Ok(())
+    }
+
+    /// Returns submission to the queue ahead of the others with the same priority, it is used for submissions which were lost by invokers.
+    /// Submission keeps time it was queued for the first time, so it doesn't lose its aging.
+    pub async fn push_front(&self, submission: Submission) {
+        self.queue.lock().await.push(Entry::new(submission, true));
+        self.notify.notify_waiters();
+    }
+
+    /// Waits until queue has submission which invoker with `capabilities` can run and takes the one with the biggest effective priority.
+    /// Returns the submission with time it has waited in the queue since it was queued last time.
+    pub async fn take(&self, capabilities: &Capabilities) -> (Submission, Duration) {
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let mut queue = self.queue.lock().await;
+                let now = Instant::now();
+                let aging_step = self.aging_step();
+                let best = queue.iter().enumerate()
+                    .filter(|(_, entry)| capabilities.satisfies(&entry.submission.requirements))
+                    .max_by_key(|(_, entry)| entry.key(now, aging_step)).map(|(index, _)| index);
+                if let Some(index) = best {
+                    let entry = queue.swap_remove(index);
+                    return (entry.submission, now.saturating_duration_since(entry.queued_at));
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Removes submission from the queue if it's still there, without `origin` submission from any testing system matches.
+    pub async fn remove(&self, origin: Option<&str>, uuid: Uuid) -> Option<Submission> {
+        let mut queue = self.queue.lock().await;
+        let index = queue.iter().position(|entry| entry.submission.uuid == uuid && origin.is_none_or(|origin| entry.submission.origin == origin))?;
+        Some(queue.swap_remove(index).submission)
+    }
+
+    /// Removes submissions which have been waiting longer than `timeout` and can't be run by any of invokers with `capabilities`.
+    pub async fn remove_unroutable(&self, capabilities: &[Capabilities], timeout: Duration) -> Vec<Submission> {
+        let mut queue = self.queue.lock().await;
+        let now = Instant::now();
+        let (unroutable, routable) = std::mem::take(&mut *queue).into_iter().partition(|entry: &Entry| {
+            now.saturating_duration_since(entry.enqueued_at) > timeout
+                && !capabilities.iter().any(|capabilities| capabilities.satisfies(&entry.submission.requirements))
+        });
+        *queue = routable;
+        unroutable.into_iter().map(|entry| entry.submission).collect()
+    }
+
+    pub async fn len(&self) -> usize {
+        self.queue.lock().await.len()
+    }
+
+    /// Lists queued submissions in order they would be taken by invoker without requirements.
+    pub async fn snapshot(&self) -> Vec<QueuedSubmission> {
+        let queue = self.queue.lock().await;
+        let now = Instant::now();
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut entries: Vec<&Entry> = queue.iter().collect();
+        let aging_step = self.aging_step();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.key(now, aging_step)));
+        entries.into_iter().map(|entry| {
+            let waited = now.saturating_duration_since(entry.enqueued_at);
+            QueuedSubmission {
+                uuid: entry.submission.uuid,
+                tests_count: entry.submission.tests_count,
+                data_size: entry.submission.data.len(),
+                priority: entry.submission.priority,
+                attempts: entry.submission.attempts,
+                enqueued_at: unix_now.saturating_sub(waited).as_secs_f64(),
+                waited: waited.as_secs_f64(),
+            }
+        }).collect()
+    }
+
+    /// Counts queued submissions by their priority.
+    pub async fn priority_distribution(&self) -> BTreeMap<i32, usize> {
+        let mut distribution = BTreeMap::new();
+        for entry in self.queue.lock().await.iter() {
+            *distribution.entry(entry.submission.priority).or_insert(0) += 1;
+        }
+        distribution
+    }
+}
@@ -3,11 +3,11 @@ use invoker_auth::{Cert, Parse};
 use reqwest::Url;
 use uuid::Uuid;
 use std::str::FromStr;
-use std::{sync::Arc, time::Duration};
+use std::sync::Arc;
 use super::{WSReader, WSWriter, TestingSystem};
 use ratchet_rs::{Error, HeaderValue};
 use tokio::{sync::Mutex};
-use crate::server::{submission::Submission, verdict::{TestResult, Verdict}};
+use crate::server::{Server, authorisation::cert_cache::CertError, capabilities::Requirements, protocol::{self, ProtocolError}, invoker::Gateway as InvokerGateway, submission::Submission, verdict::{Score, TestResult, Verdict}};
 
 
 pub struct Gateway;
@@ -25,14 +25,15 @@ impl Gateway {
         }
     }
 
-    pub async fn read_message_from(socket: &mut WSReader) -> Result<InputMessage, String> {
+    /// Outer error means that connection is broken, inner one means that testing system sent malformed message.
+    pub async fn read_message_from(socket: &mut WSReader) -> Result<Result<InputMessage, ProtocolError>, String> {
         let data = match Self::read_data_from(socket).await {
             Ok(data) => data,
             Err(err) => {
                 return Err(format!("Can't read message from ts {:?}", err));
             }
         };
-        data.try_into()
+        Ok(data.try_into())
     }
 
     pub async fn send_message_to(socket: &mut WSWriter, message: OutputMessage) -> Result<(), String> {
@@ -42,15 +43,10 @@ impl Gateway {
         }
         Ok(())
     }
-    pub async fn send_submission_verdict(testing_system: Arc<Mutex<TestingSystem>>, verdict: Verdict, submission_uuid: Uuid, tests_result: Vec<TestResult>, message: Result<(u8, Vec<u8>), String>) {
+    pub async fn send_message(testing_system: Arc<Mutex<TestingSystem>>, message: OutputMessage) -> Result<(), String> {
         let writer = testing_system.lock().await.writer.clone();
         let mut writer = writer.lock().await;
-        if let Err(err) = Self::send_message_to(&mut writer, OutputMessage::SubmissionVerdict{ verdict, submission_uuid, tests_result, message,
-        }).await {
-            log::error!("Couldn't send message | error = {}", err);
-        } else {
-            log::info!("testing_system: SubmissionVerdict message sent");
-        }
+        Self::send_message_to(&mut writer, message).await
     }
     pub async fn send_test_verdict(testing_system: Arc<Mutex<TestingSystem>>, result: TestResult, test: u16, data: Vec<u8>, submission_uuid: Uuid) {
         let writer = testing_system.lock().await.writer.clone();
@@ -63,28 +59,34 @@ impl Gateway {
             log::info!("testing_system: TestVerdict message sent");
         }
     }
-    pub async fn pinger(testing_system: Arc<Mutex<TestingSystem>>) -> Result<(), Error> {
-        let mut interval = tokio::time::interval(Duration::from_secs(30));
+    pub async fn pinger(testing_system: Arc<Mutex<TestingSystem>>, server: Arc<Mutex<Server>>) -> Result<(), Error> {
         loop {
-            interval.tick().await;
+            let interval = server.lock().await.settings.testing_systems_ping_interval;
+            tokio::time::sleep(interval).await;
             let sender = testing_system.lock().await.writer.clone();
             sender.lock().await.write_ping([0u8; 0]).await?;
         }
     }
     
-    pub async fn get_certificate_by_key(testing_system: Arc<Mutex<TestingSystem>>, key: &String) -> Result<Cert, String> {
+    /// Client errors of API mean that testing system doesn't know the key, other errors mean that it's unavailable.
+    pub async fn get_certificate_by_key(testing_system: Arc<Mutex<TestingSystem>>, key: &str) -> Result<Cert, CertError> {
         log::trace!("Trying to get authorise key from api");
-        let api_address = testing_system.lock().await.api_address.clone();
-        let mut request = reqwest::Request::new(reqwest::Method::GET, Url::from_str(&format!("http://{api_address}/get_invoker_key")).map_err(|e| e.to_string())?);
-        let _ = request.headers_mut().insert("Authorization", HeaderValue::from_str(key).map_err(|e| format!("Can't create header from str: {:?}", e))?);
-        let client = reqwest::Client::new();
+        let (api_url, client) = {
+            let testing_system_locked = testing_system.lock().await;
+            (testing_system_locked.api_url.clone(), testing_system_locked.api_client.clone())
+        };
+        let mut request = reqwest::Request::new(reqwest::Method::GET, Url::from_str(&format!("{api_url}/get_invoker_key")).map_err(|e| CertError::Unavailable(e.to_string()))?);
+        let _ = request.headers_mut().insert("Authorization", HeaderValue::from_str(key).map_err(|e| CertError::Unknown(format!("Can't create header from str: {:?}", e)))?);
         let response = client.execute(request)
-            .await.map_err(|e| format!("Can't execute request: {:?}", e))?
-            .error_for_status().map_err(|e| format!("Can't do error_for_status: {:?}", e))?;
-        let bytes = response.bytes().await.map_err(|e| format!("Can't get bytes: {:?}", e))?.to_vec();
+            .await.map_err(|e| CertError::Unavailable(format!("Can't execute request: {:?}", e)))?;
+        if response.status().is_client_error() {
+            return Err(CertError::Unknown(format!("Testing system rejected key: {}", response.status())));
+        }
+        let response = response.error_for_status().map_err(|e| CertError::Unavailable(format!("Can't do error_for_status: {:?}", e)))?;
+        let bytes = response.bytes().await.map_err(|e| CertError::Unavailable(format!("Can't get bytes: {:?}", e)))?.to_vec();
         Cert::from_bytes(
             &bytes
-        ).map_err(|e| format!("Can't get cert from bytes <{bytes:?}> : {:?}", e))
+        ).map_err(|e| CertError::Unknown(format!("Can't get cert from bytes <{bytes:?}> : {:?}", e)))
     }
 }
 
@@ -93,6 +95,9 @@ pub enum InputMessage {
     SubmissionRun {
         submission: Submission,
     },
+    CancelSubmission {
+        submission_uuid: Uuid,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -107,19 +112,53 @@ pub enum OutputMessage {
         submission_uuid: Uuid,
         verdict: Verdict,
         tests_result: Vec<TestResult>,
-        message: Result<(u8, Vec<u8>), String>,
+        message: Result<Score, String>,
+    },
+    ProtocolError {
+        message: String,
     },
 }
 
+const EXTENDED_FRAME_FLAG: u16 = 1 << 15;
+
+fn truncated(expected: usize, bytes: &[u8]) -> ProtocolError {
+    ProtocolError::TruncatedFrame { expected, length: bytes.len() }
+}
+
 impl TryFrom<Vec<u8>> for InputMessage {
-    type Error = String;
+    type Error = ProtocolError;
     fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
-        let data_uuid: [u8; 16] = bytes[0..16].try_into().unwrap_or([0u8; 16]);
-        let uuid = Uuid::from_bytes(data_uuid);
-        let test_count = u16::from_be_bytes(bytes[16..18].try_into().unwrap_or([0u8; 2]));
-        let data = bytes[18..].to_vec();
+        let uuid: [u8; 16] = bytes.get(0..16).ok_or_else(|| truncated(18, &bytes))?.try_into().expect("slice has 16 bytes");
+        let uuid = Uuid::from_bytes(uuid);
+        let test_count = u16::from_be_bytes(bytes.get(16..18).ok_or_else(|| truncated(18, &bytes))?.try_into().expect("slice has 2 bytes"));
+        if test_count & EXTENDED_FRAME_FLAG == 0 {
+            let data = bytes[18..].to_vec();
+            return Ok(Self::SubmissionRun {
+                submission: Submission::new(uuid, data, test_count),
+            });
+        }
+
+        let test_count = test_count & !EXTENDED_FRAME_FLAG;
+        let headers_length = u32::from_be_bytes(bytes.get(18..22).ok_or_else(|| truncated(22, &bytes))?.try_into().expect("slice has 4 bytes")) as usize;
+        let headers = bytes.get(22..22 + headers_length).ok_or_else(|| truncated(22 + headers_length, &bytes))?;
+        let (headers, _) = InvokerGateway::parse_headers(headers.to_vec());
+        match headers.get("TYPE").map(String::as_str) {
+            None | Some("RUN") => {},
+            Some("CANCEL") => return Ok(Self::CancelSubmission {
+                submission_uuid: uuid,
+            }),
+            Some(message_type) => return Err(ProtocolError::UnknownType(message_type.to_string())),
+        }
+        let data = bytes[22 + headers_length..].to_vec();
+
+        let mut submission = Submission::new(uuid, data, test_count);
+        if let Some(priority) = protocol::optional_number(&headers, "PRIORITY")? {
+            submission.priority = priority;
+        }
+        submission.deadline = protocol::optional_number(&headers, "DEADLINE")?;
+        submission.requirements = Requirements::from_headers(&headers);
         Ok(Self::SubmissionRun {
-            submission: Submission::new(uuid, data, test_count),
+            submission,
         })
     }
 }
@@ -134,8 +173,17 @@ impl From<OutputMessage> for Vec<u8> {
             },
             OutputMessage::SubmissionVerdict { submission_uuid, verdict, tests_result, message } => {
                 match message {
-                    Ok((sum, groups)) => {
-                        let mut result: Vec<u8> = format!("TYPE VERDICT\nSUBMISSION {}\nVERDICT {}\nSUM {}\nGROUPS {}\nDATA\n", submission_uuid, String::from(verdict), sum, groups.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(" ")).bytes().collect();
+                    Ok(score) => {
+                        let join = |values: Vec<String>| values.join(" ");
+                        let mut headers = format!("TYPE VERDICT\nSUBMISSION {}\nVERDICT {}\nSUM {}\nGROUPS {}\n", submission_uuid, String::from(verdict), score.sum, join(score.groups.iter().map(|group| group.points.to_string()).collect()));
+                        // group details are sent only if invoker sent them for every group
+                        if let Some(max_points) = score.groups.iter().map(|group| group.max_points.map(|max_points| max_points.to_string())).collect::<Option<Vec<_>>>().filter(|max_points| !max_points.is_empty()) {
+                            headers.push_str(&format!("GROUPS_MAX {}\n", join(max_points)));
+                        }
+                        if let Some(verdicts) = score.groups.iter().map(|group| group.verdict.clone().map(String::from)).collect::<Option<Vec<_>>>().filter(|verdicts| !verdicts.is_empty()) {
+                            headers.push_str(&format!("GROUPS_VERDICTS {}\n", join(verdicts)));
+                        }
+                        let mut result: Vec<u8> = format!("{headers}DATA\n").bytes().collect();
                         for test_result in tests_result {
                             result.append(&mut format!("{}\n", String::from(test_result)).bytes().collect::<Vec<u8>>());
                         }
@@ -149,8 +197,76 @@ impl From<OutputMessage> for Vec<u8> {
                         result
                     }
                 }
-            }
+            },
+            OutputMessage::ProtocolError { message } => {
+                format!("TYPE PROTOCOL_ERROR\nMESSAGE {}\n", message).bytes().collect()
+            },
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: Uuid = Uuid::from_u128(0x67e55044_10b1_426f_9247_bb680e5fe0c8);
+
+    fn extended_frame(tests_count: u16, headers: &str, data: &[u8]) -> Vec<u8> {
+        let mut bytes = UUID.as_bytes().to_vec();
+        bytes.extend_from_slice(&(tests_count | EXTENDED_FRAME_FLAG).to_be_bytes());
+        bytes.extend_from_slice(&(headers.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(headers.as_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn legacy_frame_is_submission() {
+        let mut bytes = UUID.as_bytes().to_vec();
+        bytes.extend_from_slice(&5u16.to_be_bytes());
+        bytes.extend_from_slice(b"code");
+        let Ok(InputMessage::SubmissionRun { submission }) = InputMessage::try_from(bytes) else {
+            panic!("frame isn't parsed");
+        };
+        assert_eq!((submission.uuid, submission.tests_count, submission.data), (UUID, 5, b"code".to_vec()));
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        assert_eq!(InputMessage::try_from(vec![0; 10]).unwrap_err(), ProtocolError::TruncatedFrame { expected: 18, length: 10 });
+        assert_eq!(InputMessage::try_from(vec![0; 17]).unwrap_err(), ProtocolError::TruncatedFrame { expected: 18, length: 17 });
+        let bytes = extended_frame(1, "", &[])[..20].to_vec();
+        assert_eq!(InputMessage::try_from(bytes).unwrap_err(), ProtocolError::TruncatedFrame { expected: 22, length: 20 });
+        let mut bytes = extended_frame(1, "TYPE RUN\n", &[]);
+        bytes.truncate(25);
+        assert_eq!(InputMessage::try_from(bytes).unwrap_err(), ProtocolError::TruncatedFrame { expected: 31, length: 25 });
+    }
+
+    #[test]
+    fn extended_frame_sets_priority_deadline_and_requirements() {
+        let bytes = extended_frame(3, "TYPE RUN\nPRIORITY -2\nDEADLINE 60\nLANG cpp\nFEATURES gpu\n", b"code");
+        let Ok(InputMessage::SubmissionRun { submission }) = InputMessage::try_from(bytes) else {
+            panic!("frame isn't parsed");
+        };
+        assert_eq!((submission.tests_count, submission.priority, submission.deadline), (3, -2, Some(60)));
+        assert_eq!(submission.requirements.lang.as_deref(), Some("cpp"));
+        assert_eq!(submission.requirements.features, vec!["gpu".to_string()]);
+        assert_eq!(submission.data, b"code");
+    }
+
+    #[test]
+    fn malformed_extended_frames_are_rejected() {
+        assert_eq!(InputMessage::try_from(extended_frame(1, "PRIORITY high\n", &[])).unwrap_err(), ProtocolError::BadNumber { header: "PRIORITY", value: "high".to_string() });
+        assert_eq!(InputMessage::try_from(extended_frame(1, "DEADLINE -5\n", &[])).unwrap_err(), ProtocolError::BadNumber { header: "DEADLINE", value: "-5".to_string() });
+        assert_eq!(InputMessage::try_from(extended_frame(1, "TYPE PAUSE\n", &[])).unwrap_err(), ProtocolError::UnknownType("PAUSE".to_string()));
+    }
+
+    #[test]
+    fn cancel_frame_names_submission() {
+        let Ok(InputMessage::CancelSubmission { submission_uuid }) = InputMessage::try_from(extended_frame(0, "TYPE CANCEL\n", &[])) else {
+            panic!("frame isn't parsed");
+        };
+        assert_eq!(submission_uuid, UUID);
+    }
+}
@@ -1,72 +1,127 @@
 pub mod gateway;
-pub use gateway::{Gateway, InputMessage};
+pub use gateway::{Gateway, InputMessage, OutputMessage};
 
 use std::sync::Arc;
 
-use ratchet_rs::{Error, Receiver, Sender, SubprotocolRegistry, WebSocketConfig};
+use ratchet_rs::{Error, Receiver, Sender, WebSocketConfig};
 use ratchet_deflate::{Compression, DeflateConfig, DeflateDecoder, DeflateEncoder, DeflateExtProvider};
+use serde::Serialize;
 use tokio::{net::TcpStream, sync::Mutex};
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
 
-use crate::{COMPRESSION_LEVEL, MAX_MESSAGE_SIZE};
+use super::{protocol::{ErrorBudget, TestingSystemProtocol}, testing_system_side::Endpoint, tls::MaybeTlsStream, Server, TestingSystemSide};
 
-use super::{Server, TestingSystemSide};
-
-pub type WSReader = Receiver<TcpStream, DeflateDecoder>;
-pub type WSWriter = Sender<TcpStream, DeflateEncoder>;
+pub type WSReader = Receiver<MaybeTlsStream, DeflateDecoder>;
+pub type WSWriter = Sender<MaybeTlsStream, DeflateEncoder>;
 
 pub struct TestingSystem {
     writer: Arc<Mutex<WSWriter>>,
     reader: Arc<Mutex<WSReader>>,
-    api_address: String,
+    api_url: String,
+    api_client: reqwest::Client,
+    origin: String,
+    protocol: TestingSystemProtocol,
+    protocol_errors: ErrorBudget,
+}
+
+#[derive(Debug, Clone)]
+#[derive(Serialize)]
+pub struct TestingSystemStatus {
+    pub protocol: TestingSystemProtocol,
 }
 
 impl TestingSystem {
-    pub async fn connect_to(ts_ip: &str, api_addr: &str, url: &str) -> Result<Self, Error> {
-        let stream = TcpStream::connect(ts_ip).await?;
+    pub async fn connect_to(endpoint: &Endpoint, max_message_size: usize, compression_level: u32) -> Result<Self, Error> {
+        let stream = TcpStream::connect(&endpoint.address).await?;
+        let stream = match &endpoint.tls {
+            Some(tls) => {
+                let host = endpoint.address.rsplit_once(':').map_or(endpoint.address.as_str(), |(host, _)| host).trim_matches(['[', ']']);
+                let server_name = ServerName::try_from(host.to_string())
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+                let stream = TlsConnector::from(tls.clone()).connect(server_name, stream).await?;
+                MaybeTlsStream::Tls(Box::new(stream.into()))
+            },
+            None => MaybeTlsStream::Plain(stream),
+        };
         let socket = ratchet_rs::subscribe_with(
             WebSocketConfig {
-                max_message_size: MAX_MESSAGE_SIZE,    // 64MB максимальный размер сообщения
+                max_message_size,
             },
-            stream, url,
+            stream, &endpoint.url,
             DeflateExtProvider::with_config(
                 DeflateConfig {
-                    compression_level: Compression::new(COMPRESSION_LEVEL),
+                    compression_level: Compression::new(compression_level),
                     ..Default::default()
                 }
             ),
-            SubprotocolRegistry::default()
-        ).await?.into_websocket();
+            TestingSystemProtocol::registry()
+        ).await?;
+        let protocol = TestingSystemProtocol::negotiated(socket.subprotocol.as_ref());
 
-        let (writer, reader) = socket.split()?;
+        let (writer, reader) = socket.into_websocket().split()?;
 
-        log::info!("testing_system_side: Connected to tssystem");
-        Ok(Self::new(reader, writer, api_addr.to_string()))
+        log::info!("testing_system_side: Connected to tssystem | protocol = {}", protocol.name());
+        Ok(Self::new(reader, writer, endpoint.api_url.clone(), endpoint.api_client.clone(), endpoint.address.clone(), protocol))
     }
-    pub fn new(reader: WSReader, writer: WSWriter, api_address: String) -> Self {
+    pub fn new(reader: WSReader, writer: WSWriter, api_url: String, api_client: reqwest::Client, origin: String, protocol: TestingSystemProtocol) -> Self {
         Self {
             writer: Arc::new(Mutex::new(writer)),
             reader: Arc::new(Mutex::new(reader)),
-            api_address,
+            api_url,
+            api_client,
+            origin,
+            protocol,
+            protocol_errors: ErrorBudget::default(),
         }
     }
+
+    pub fn get_status(&self) -> TestingSystemStatus {
+        TestingSystemStatus { protocol: self.protocol }
+    }
     pub async fn message_handler(testing_system: Arc<Mutex<Self>>, server: Arc<Mutex<Server>>) -> Result<String, String> {
-        let reader = {
-            testing_system.lock().await.reader.clone()
+        let (reader, origin) = {
+            let testing_system_locked = testing_system.lock().await;
+            (testing_system_locked.reader.clone(), testing_system_locked.origin.clone())
         };
         let mut reader_locked = reader.lock().await;
         'lp: loop {
             match Gateway::read_message_from(&mut reader_locked).await {
-                Ok(message) => {
+                Ok(Ok(message)) => {
                     match message {
-                        InputMessage::SubmissionRun { submission } => {
-                            log::info!("testing_system_side: Recieved a message | submission = {:?}", submission.uuid);
+                        InputMessage::SubmissionRun { mut submission } => {
+                            log::info!("testing_system_side: Recieved a message | submission = {:?} | origin = {}", submission.uuid, origin);
+                            submission.origin = origin.clone();
                             tokio::spawn(TestingSystemSide::add_submission(server.clone(), submission));
                         },
+                        InputMessage::CancelSubmission { submission_uuid } => {
+                            log::info!("testing_system_side: Recieved a cancel message | submission = {:?} | origin = {}", submission_uuid, origin);
+                            let (server, origin) = (server.clone(), origin.clone());
+                            tokio::spawn(async move {
+                                // testing system can cancel only its own submissions
+                                if let Err(err) = Server::cancel_submission(server, Some(&origin), submission_uuid).await {
+                                    log::error!("testing_system_side: Couldn't cancel submission | error = {} | submission = {:?}", err, submission_uuid);
+                                }
+                            });
+                        },
+                    }
+                },
+                Ok(Err(err)) => {
+                    log::error!("testing_system_side: Recieved a unparseable message | error = {} | origin = {}", err, origin);
+                    let (budget, window) = {
+                        let server_locked = server.lock().await;
+                        server_locked.metrics.protocol_error("testing_system");
+                        (server_locked.settings.protocol_error_budget, server_locked.settings.protocol_error_window)
+                    };
+                    if let Err(err) = Gateway::send_message(testing_system.clone(), OutputMessage::ProtocolError { message: err.to_string() }).await {
+                        log::error!("testing_system_side: Couldn't send PROTOCOL_ERROR message | error = {}", err);
+                    }
+                    if !testing_system.lock().await.protocol_errors.spend(budget, window) {
+                        break 'lp Err(format!("testing system exceeded error budget, last error: {err}"));
                     }
                 },
                 Err(err) => {
-                    log::error!("testing_system_side: Recieved a unparseable message | error = {:?}", err);
-                    break 'lp Err("testing system sent wrong message".to_string());
+                    log::error!("testing_system_side: Couldn't read message | error = {}", err);
+                    break 'lp Err("testing system connection is broken".to_string());
                 }
             }
         }
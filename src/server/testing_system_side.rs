@@ -1,67 +1,175 @@
-use tokio::sync::{mpsc, Mutex};
-use std::sync::Arc;
-use crate::server::testing_system::{self, TestingSystem};
-use super::Server;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use std::{collections::{HashMap, VecDeque}, sync::Arc, time::Duration};
+use tokio_rustls::rustls::ClientConfig;
+use crate::server::testing_system::{self, TestingSystem, TestingSystemStatus};
+use super::{events::Event, Server};
 use super::submission::Submission;
-use super::verdict::TestResult;
-use super::verdict;
+use super::verdict::{Score, TestResult, Verdict};
+
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+const MAX_PENDING_VERDICTS: usize = 10000;
+
+/// Testing system which `invoker-manager` connects to, its address is used as origin of submissions.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub address: String,
+    pub url: String, // ws:// or wss:// url of testing system
+    pub api_url: String, // http:// or https:// url of testing system API
+    pub api_client: reqwest::Client,
+    pub tls: Option<Arc<ClientConfig>>, // with it connection to `url` is wrapped into TLS
+}
 
 pub struct TestingSystemSide {
-    pub testing_system: Option<Arc<Mutex<TestingSystem>>>,
-    pub submissions_pool_sender: Arc<Mutex<mpsc::Sender<Submission>>>,
+    pub testing_systems: HashMap<String, Arc<Mutex<TestingSystem>>>, // connected testing systems by origin
+    pending_verdicts: HashMap<String, VecDeque<testing_system::gateway::OutputMessage>>,
 }
 
 impl TestingSystemSide {
-    pub fn new(sps: mpsc::Sender<Submission>) -> Self {
+    pub fn new() -> Self {
         Self {
-            submissions_pool_sender: Arc::new(Mutex::new(sps)),
-            testing_system: None,
+            testing_systems: HashMap::new(),
+            pending_verdicts: HashMap::new(),
         }
     }
-    pub async fn start(server: Arc<Mutex<Server>>, ts_ip: &str, api_addr: &str, url: &str) -> Result<(), String> {
-        let testing_system = match TestingSystem::connect_to(ts_ip, api_addr, url).await {
-            Ok(ts) => ts,
-            Err(error) => {
-                log::error!("testing_system_side: Can't open connection to testing system side | error = {} | ip = {} | url = {}", error.to_string(), ts_ip, url);
-                return Err("Can't open connection to testing system side".to_string());
-            },
-        };
-        log::trace!("testing_system_side: Started");
 
-        server.lock().await.testing_system_side.testing_system = Some(Arc::new(Mutex::new(testing_system)));
-        if let Some(testing_system) = { server.lock().await.testing_system_side.testing_system.clone() } {
-            log::trace!("testing_system_side: Connected to testing system");
+    pub fn get_testing_system(&self, origin: &str) -> Option<Arc<Mutex<TestingSystem>>> {
+        self.testing_systems.get(origin).cloned()
+    }
+
+    pub async fn get_testing_systems_status(&self) -> HashMap<String, TestingSystemStatus> {
+        let testing_systems = self.testing_systems.clone();
+        let mut map = HashMap::new();
+        for (origin, testing_system) in testing_systems {
+            map.insert(origin, testing_system.lock().await.get_status());
+        }
+        map
+    }
+
+    /// Keeps connection to testing system alive: reconnects with exponential backoff and jitter every time it drops.
+    pub async fn start(server: Arc<Mutex<Server>>, endpoint: Endpoint) -> Result<(), String> {
+        let origin = endpoint.address.clone();
+        let mut delay = RECONNECT_INITIAL_DELAY;
+        loop {
+            let (max_message_size, compression_level) = {
+                let server_locked = server.lock().await;
+                (server_locked.settings.max_message_size, server_locked.settings.compression_level)
+            };
+            match TestingSystem::connect_to(&endpoint, max_message_size, compression_level).await {
+                Ok(testing_system) => {
+                    log::info!("testing_system_side: Connected to testing system | ip = {} | url = {}", endpoint.address, endpoint.url);
+                    delay = RECONNECT_INITIAL_DELAY;
 
-            tokio::spawn(testing_system::Gateway::pinger(testing_system.clone()));
+                    let testing_system = Arc::new(Mutex::new(testing_system));
+                    {
+                        let mut server_locked = server.lock().await;
+                        server_locked.testing_system_side.testing_systems.insert(origin.clone(), testing_system.clone());
+                        server_locked.metrics.testing_system_connected(&origin, true);
+                    }
+                    let pinger = tokio::spawn(testing_system::Gateway::pinger(testing_system.clone(), server.clone()));
+                    Self::flush_pending_verdicts(server.clone(), &origin, testing_system.clone()).await;
 
-            let result = TestingSystem::message_handler(testing_system, server.clone()).await;
-            if let Err(error) = result {
-                return Err(error);
+                    let result = TestingSystem::message_handler(testing_system, server.clone()).await;
+                    pinger.abort();
+                    {
+                        let mut server_locked = server.lock().await;
+                        server_locked.testing_system_side.testing_systems.remove(&origin);
+                        server_locked.metrics.testing_system_connected(&origin, false);
+                    }
+                    match result {
+                        Ok(message) => log::warn!("testing_system_side: Connection to testing system closed | message = {} | origin = {}", message, origin),
+                        Err(error) => log::error!("testing_system_side: Connection to testing system lost | error = {} | origin = {}", error, origin),
+                    }
+                },
+                Err(error) => {
+                    server.lock().await.metrics.testing_system_connected(&origin, false);
+                    log::error!("testing_system_side: Can't open connection to testing system side | error = {} | ip = {} | url = {}", error, endpoint.address, endpoint.url);
+                },
             }
-        } else {
-            log::error!("testing_system_side: Can't connect to testing system");
 
-            return Err("Error::ConnectionClosed".to_string())
+            let jittered = delay.mul_f64(0.5 + rand::random::<f64>());
+            log::info!("testing_system_side: Reconnecting to testing system | delay = {:?}", jittered);
+            tokio::time::sleep(jittered).await;
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        }
+    }
+
+    async fn flush_pending_verdicts(server: Arc<Mutex<Server>>, origin: &str, testing_system: Arc<Mutex<TestingSystem>>) {
+        loop {
+            let Some(message) = server.lock().await.testing_system_side.pending_verdicts.get_mut(origin).and_then(|pending| pending.pop_front()) else {
+                return;
+            };
+            if let Err(err) = testing_system::Gateway::send_message(testing_system.clone(), message.clone()).await {
+                log::error!("testing_system_side: Couldn't flush pending verdict | error = {} | origin = {}", err, origin);
+                server.lock().await.testing_system_side.pending_verdicts.entry(origin.to_string()).or_default().push_front(message);
+                return;
+            }
         }
-        Ok(())
     }
 
+    fn push_pending_verdict(&mut self, origin: &str, message: testing_system::gateway::OutputMessage) {
+        let pending_verdicts = self.pending_verdicts.entry(origin.to_string()).or_default();
+        if pending_verdicts.len() >= MAX_PENDING_VERDICTS {
+            log::error!("testing_system_side: Too many pending verdicts, dropping the oldest one | origin = {}", origin);
+            pending_verdicts.pop_front();
+        }
+        pending_verdicts.push_back(message);
+    }
+
+    /// Sends submission verdict to testing system which submission came from, if it's disconnected verdict is kept until reconnection.
+    pub async fn send_submission_verdict(server: Arc<Mutex<Server>>, origin: String, verdict: Verdict, submission_uuid: Uuid, tests_result: Vec<TestResult>, message: Result<Score, String>) {
+        {
+            let server_locked = server.lock().await;
+            server_locked.metrics.submission_completed((origin.clone(), submission_uuid), &String::from(verdict.clone()));
+            server_locked.events.publish(Event::SubmissionVerdict { submission_uuid, verdict: verdict.clone() });
+        }
+        let message = testing_system::gateway::OutputMessage::SubmissionVerdict { submission_uuid, verdict, tests_result, message };
+        let testing_system = server.lock().await.testing_system_side.get_testing_system(&origin);
+        let Some(testing_system) = testing_system else {
+            log::warn!("testing_system_side: Testing system isn't connected, verdict is postponed | submission_uuid = {} | origin = {}", submission_uuid, origin);
+            server.lock().await.testing_system_side.push_pending_verdict(&origin, message);
+            return;
+        };
+        if let Err(err) = testing_system::Gateway::send_message(testing_system, message.clone()).await {
+            log::error!("testing_system_side: Couldn't send verdict, it is postponed | error = {} | submission_uuid = {} | origin = {}", err, submission_uuid, origin);
+            server.lock().await.testing_system_side.push_pending_verdict(&origin, message);
+        }
+    }
+
+    /// Sends test verdict to testing system which submission came from, if it's disconnected verdict is dropped.
+    pub async fn send_test_verdict(server: Arc<Mutex<Server>>, origin: String, result: TestResult, test: u16, data: Vec<u8>, submission_uuid: Uuid) {
+        let testing_system = server.lock().await.testing_system_side.get_testing_system(&origin);
+        let Some(testing_system) = testing_system else {
+            log::warn!("testing_system_side: Testing system isn't connected, test verdict is dropped | submission_uuid = {} | test = {} | origin = {}", submission_uuid, test, origin);
+            return;
+        };
+        testing_system::Gateway::send_test_verdict(testing_system, result, test, data, submission_uuid).await;
+    }
 
     pub async fn add_submission(server: Arc<Mutex<Server>>, submission: Submission) -> Result<(), String> {
         let submission_uuid = submission.uuid;
         let tests_count = submission.tests_count;
-        {
-            let mut server_locked = server.lock().await;
-            server_locked.tests_results.insert(submission_uuid, vec![TestResult::new(); tests_count as usize]);
+        let origin = submission.origin.clone();
+        let queued = Event::SubmissionQueued { submission_uuid, tests_count, priority: submission.priority, attempts: submission.attempts };
+        Server::reset_tests_result(server.clone(), submission.key(), tests_count).await;
+        let journal = {
+            let server_locked = server.lock().await;
+            server_locked.metrics.submission_received(submission.key());
+            server_locked.journal.clone()
+        };
+        if let Err(err) = journal.enqueue(&submission).await {
+            log::error!("Couldn't write submission to journal, it's rejected | error = {} | submission_uuid = {}", err, submission_uuid);
+            Server::fail_submission(server.clone(), submission_uuid, origin, "Submission couldn't be saved to journal.".to_string()).await;
+            return Err(format!("Couldn't write submission to journal: {err}"));
         }
-        let submissions_pool_sender = server.lock().await.testing_system_side.submissions_pool_sender.clone();
-        if let Err(error) = submissions_pool_sender.lock().await.send(submission).await {
-            if let Some(testing_system) = server.lock().await.testing_system_side.testing_system.clone() {
-                tokio::spawn(testing_system::Gateway::send_submission_verdict(testing_system, verdict::Verdict::TE, submission_uuid, Vec::new(), Err("Couldn't add new submission to queue.".to_string())));
-            }
-            return Err(error.to_string());
+        let submissions_pool = server.lock().await.submissions_pool.clone();
+        if submissions_pool.push_back(submission).await.is_err() {
+            Server::fail_submission(server.clone(), submission_uuid, origin, "Couldn't add new submission to queue.".to_string()).await;
+            return Err("Submissions queue is full".to_string());
         }
-        log::trace!("New submission added to queue | uuid = {} | tests_count = {}", submission_uuid, tests_count);
+        server.lock().await.events.publish(queued);
+        log::trace!("New submission added to queue | uuid = {} | tests_count = {} | origin = {}", submission_uuid, tests_count, origin);
         Ok(())
     }
 }
@@ -0,0 +1,169 @@
+use std::{io, path::PathBuf, pin::Pin, sync::{Arc, RwLock}, task::{Context, Poll}, time::{Duration, SystemTime}};
+
+use tokio::{io::{AsyncRead, AsyncWrite, ReadBuf}, net::TcpStream};
+use tokio_rustls::rustls::{crypto::{aws_lc_rs, CryptoProvider}, pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer}, server::WebPkiClientVerifier, ClientConfig, RootCertStore, ServerConfig};
+
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Connection which is either plain TCP or TLS over TCP.
+pub enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<tokio_rustls::TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Provider is picked explicitly, because dependencies may enable several of them.
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(aws_lc_rs::default_provider())
+}
+
+fn load_roots(path: &PathBuf) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(path).map_err(|err| format!("Can't read CA {}: {err}", path.display()))? {
+        let cert = cert.map_err(|err| format!("Can't read CA {}: {err}", path.display()))?;
+        roots.add(cert).map_err(|err| format!("Invalid CA {}: {err}", path.display()))?;
+    }
+    Ok(roots)
+}
+
+/// PEM files of listener's certificate chain and private key, with `client_ca` clients have to present certificate signed by it.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub client_ca_path: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    fn modified(&self) -> Option<SystemTime> {
+        [Some(&self.cert_path), Some(&self.key_path), self.client_ca_path.as_ref()].into_iter().flatten()
+            .filter_map(|path| path.metadata().and_then(|metadata| metadata.modified()).ok())
+            .max()
+    }
+
+    fn load(&self) -> Result<ServerConfig, String> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path).and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| format!("Can't read certificate {}: {err}", self.cert_path.display()))?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|err| format!("Can't read private key {}: {err}", self.key_path.display()))?;
+        let builder = ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()
+            .map_err(|err| format!("Can't configure TLS: {err}"))?;
+        let builder = match &self.client_ca_path {
+            Some(client_ca_path) => {
+                let roots = load_roots(client_ca_path)?;
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider()).build()
+                    .map_err(|err| format!("Can't build client certificate verifier: {err}"))?;
+                builder.with_client_cert_verifier(verifier)
+            },
+            None => builder.with_no_client_auth(),
+        };
+        builder.with_single_cert(certs, key).map_err(|err| format!("Invalid certificate or key: {err}"))
+    }
+}
+
+/// TLS acceptor which reloads certificate when its files change.
+pub struct TlsAcceptor {
+    config: TlsConfig,
+    acceptor: RwLock<tokio_rustls::TlsAcceptor>,
+    modified: Option<SystemTime>, // of files certificate was loaded from at start
+}
+
+impl TlsAcceptor {
+    pub fn new(config: TlsConfig) -> Result<Arc<Self>, String> {
+        let modified = config.modified();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config.load()?));
+        log::info!("tls: Certificate loaded | cert = {} | client_ca = {:?}", config.cert_path.display(), config.client_ca_path);
+        Ok(Arc::new(Self { config, acceptor: RwLock::new(acceptor), modified }))
+    }
+
+    /// Returns acceptor with the newest certificate.
+    pub fn acceptor(&self) -> tokio_rustls::TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    /// Checks certificate files every few seconds outside of accept loop, if new certificate can't be loaded the previous one is kept.
+    pub async fn watcher(tls_acceptor: Arc<Self>) {
+        let mut modified = tls_acceptor.modified;
+        loop {
+            tokio::time::sleep(CERT_CHECK_INTERVAL).await;
+            let config = tls_acceptor.config.clone();
+            let reloaded = tokio::task::spawn_blocking(move || {
+                let current = config.modified();
+                (current != modified).then(|| (current, config.load()))
+            }).await;
+            let Ok(Some((current, reloaded))) = reloaded else {
+                continue;
+            };
+            modified = current;
+            match reloaded {
+                Ok(config) => {
+                    *tls_acceptor.acceptor.write().unwrap() = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+                    log::info!("tls: Certificate reloaded | cert = {}", tls_acceptor.config.cert_path.display());
+                },
+                Err(err) => log::error!("tls: Couldn't reload certificate, the previous one is used | error = {}", err),
+            }
+        }
+    }
+}
+
+/// CA bundle which servers' certificates are verified with, by default well-known CAs are used, and optional client certificate.
+#[derive(Debug, Clone, Default)]
+pub struct ClientTlsConfig {
+    pub ca_path: Option<PathBuf>,
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+}
+
+impl ClientTlsConfig {
+    pub fn load(&self) -> Result<ClientConfig, String> {
+        let roots = match &self.ca_path {
+            Some(ca_path) => load_roots(ca_path)?,
+            None => RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() },
+        };
+        let builder = ClientConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()
+            .map_err(|err| format!("Can't configure TLS: {err}"))?
+            .with_root_certificates(roots);
+        match (&self.cert_path, &self.key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let certs = CertificateDer::pem_file_iter(cert_path).and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|err| format!("Can't read client certificate {}: {err}", cert_path.display()))?;
+                let key = PrivateKeyDer::from_pem_file(key_path)
+                    .map_err(|err| format!("Can't read client private key {}: {err}", key_path.display()))?;
+                builder.with_client_auth_cert(certs, key).map_err(|err| format!("Invalid client certificate or key: {err}"))
+            },
+            (None, None) => Ok(builder.with_no_client_auth()),
+            _ => Err("Both client certificate and key should be specified".to_string()),
+        }
+    }
+}
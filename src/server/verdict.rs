@@ -24,6 +24,23 @@ pub enum Verdict {
     UV,
 }
 
+/// Points of one group of tests, `max_points` and `verdict` are known only if invoker sent them.
+#[derive(Debug, Clone)]
+#[derive(Serialize)]
+pub struct GroupScore {
+    pub points: f64,
+    pub max_points: Option<f64>,
+    pub verdict: Option<Verdict>,
+}
+
+/// Score of submission tested with `OK` verdict.
+#[derive(Debug, Clone)]
+#[derive(Serialize)]
+pub struct Score {
+    pub sum: f64,
+    pub groups: Vec<GroupScore>,
+}
+
 impl From<&String> for Verdict {
     fn from(value: &String) -> Self {
         match value.as_str() {